@@ -0,0 +1,20 @@
+use assembler::parser::parse_raw;
+use criterion::{Criterion, black_box, criterion_group, criterion_main};
+
+// Around 10k lines of labels, local labels, a macro, a conditional and continued `.db` lines
+fn program(blocks: usize) -> String {
+    let mut source = String::from(".macro pair a, b\n    push a\n    push b\n.endm\n.ifdef UNSET\n    nop\n.endif\n");
+    for i in 0..blocks {
+        source += &format!("loop{}: set r1, {} // count down\n.again: sub r1, 1\n    jmpnz .again\n    pair r2, r3\n", i, i % 256);
+        source += &format!("    ldr r4, table{0}\n    call loop{0}\n    pop r3; pop r2\ntable{0}: .db 1 2 3 \\\n    4\n", i);
+    }
+    source
+}
+
+fn parse(c: &mut Criterion) {
+    let source = program(1250);
+    c.bench_function("parse 10k lines", |b| b.iter(|| parse_raw(black_box(&source), None)));
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
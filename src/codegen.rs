@@ -1,5 +1,13 @@
-use crate::instruction::RegisterMap;
-use crate::parser::{Line, LineData, Log, Parameters, DataByte, Directive};
+use crate::expression::Expression;
+use crate::instruction::{Cpu, Instruction, OperandMode, RegisterMap, closest_name};
+use crate::parser::{ByteSelect, Code, Line, LineData, Log, Parameters, DataByte, Directive};
+use crate::prelude::*;
+use utils::Iter;
+
+use alloc::collections::{BTreeMap, BTreeSet, btree_map::Entry};
+use alloc::sync::Arc;
+#[cfg(feature = "std")]
+use std::io::{self, Write};
 
 #[derive(Clone, Copy, Debug)]
 pub struct Register(u8);
@@ -12,135 +20,957 @@ impl Register {
             None
         }
     }
+    
+    pub fn as_u8(self) -> u8 {
+        self.0
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CodegenOptions {
+    /// Unresolved symbols become relocations instead of errors
+    pub relocatable: bool,
+    /// Warns about labels that are never referenced or exported
+    pub warn_unused_labels: bool,
+    /// Warns about instructions left on an odd address by the `.db` bytes before them
+    pub warn_align: bool,
+    /// Byte used to pad gaps left by `.line`
+    pub fill: u8,
+    /// Reports every warning as an error
+    pub warnings_as_errors: bool,
+    /// Warnings with these codes are dropped
+    pub allow: Vec<Code>,
+    /// Instructions the core doesn't have are errors
+    pub cpu: Cpu,
+    /// Byte order of every 16-bit value in the output
+    pub endian: Endian,
+}
+
+/// Byte order of 16-bit immediates and addresses
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Iter)]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+impl Endian {
+    /// Name used on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Little => "little",
+            Self::Big    => "big",
+        }
+    }
+    
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::iter().copied().find(|endian| endian.name() == name)
+    }
+    
+    pub fn bytes(&self, value: u16) -> [u8; 2] {
+        match self {
+            Self::Little => value.to_le_bytes(),
+            Self::Big    => value.to_be_bytes(),
+        }
+    }
+}
+
+/// A reference to a symbol that a linker has to patch in
+#[derive(Clone, Debug, PartialEq)]
+pub struct Relocation {
+    pub symbol: String,
+    /// Position of the value in the output
+    pub offset: u16,
+    /// Size of the value in bytes, stored in the byte order of `CodegenOptions::endian`
+    pub width: u8,
+    /// Relative values are taken from the start of the instruction, one byte before `offset`
+    pub relative: bool,
+}
+
+/// Everything a linker needs besides the code itself
+#[derive(Clone, Debug, Default)]
+pub struct LinkInfo {
+    /// Symbols exported with `.global` and their addresses, in declaration order
+    pub globals: Vec<(String, u16)>,
+    pub relocations: Vec<Relocation>,
+}
+
+/// Bytes in the output that came from one line of source
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineMapping {
+    pub range: core::ops::Range<usize>,
+    pub origin: Arc<String>,
+    /// Zero based, like `Line::line`
+    pub line: usize,
+}
+
+// Where a label points and where it was defined
+struct LabelDefinition {
+    address: usize,
+    line: usize,
+    origin: Arc<String>,
+}
+
+// Whether the parser could have produced `params` for an instruction with this operand mode
+fn accepts(mode: OperandMode, params: &Parameters) -> bool {
+    use Parameters::*;
+    match mode {
+        OperandMode::NoParams => matches!(params, None),
+        OperandMode::OneRegister => matches!(params, OneRegister(_)),
+        OperandMode::OneOrTwoRegisters => matches!(params, OneRegister(_) | TwoRegisters(..)),
+        OperandMode::OneRegisterAndImmediate => matches!(params, OneRegisterImmediate(..)),
+        OperandMode::OneRegisterAndLongImmediate => matches!(params, OneRegisterLongImmediate(..) | OneRegisterExpression(..)),
+        OperandMode::TwoRegisters => matches!(params, TwoRegisters(..)),
+        OperandMode::TwoRegistersOrImmediate => matches!(params, TwoRegisters(..) | OneRegisterImmediate(..) | TwoRegistersImmediate(..)),
+        OperandMode::TwoRegistersAndLongImmediate => matches!(params, TwoRegistersLongImmediate(..)),
+        OperandMode::TwoRegistersOrLongImmediate => matches!(params, TwoRegisters(..) | LongImmediate(_) | Label(_) | Expression(_)),
+    }
+}
+
+// One encoded instruction, with where its 16-bit link value goes and whether that value is relative
+struct Encoded {
+    bytes: Vec<u8>,
+    link: Option<(Expression, usize, bool)>,
+}
+
+// Encodes an instruction whose operands passed `accepts`, values still to be linked are left as 0xDEAD
+fn encode(name: Instruction, params: &Parameters, endian: Endian) -> Encoded {
+    let asm_info = name.assemble_info();
+    
+    enum Usage {
+        Register(Register, Register, Option<u8>),
+        WideImmediate(Register, Register, u16),
+        LongImmediate(u16),
+        Unresolved(Expression),
+        WideAddress(Register, Result<u16, Expression>),
+    }
+    
+    let usage: Usage = match *params {
+        Parameters::None => Usage::Register(Register(0), Register(0), None),
+        Parameters::Label(ref label) => Usage::Unresolved(Expression::Symbol(label.clone())),
+        Parameters::Expression(ref expr) => Usage::Unresolved(expr.clone()),
+        Parameters::OneRegister(a) => Usage::Register(a, a, None),
+        Parameters::LongImmediate(i) => Usage::LongImmediate(i),
+        Parameters::TwoRegisters(a, b) => Usage::Register(a, b, None),
+        Parameters::OneRegisterImmediate(a, i) => Usage::Register(a, a, Some(i)),
+        Parameters::TwoRegistersImmediate(a, b, i) => Usage::Register(a, b, Some(i)),
+        Parameters::TwoRegistersLongImmediate(a, b, i) => Usage::WideImmediate(a, b, i),
+        // Addresses that fit in a byte keep the short form
+        Parameters::OneRegisterLongImmediate(a, i) if i <= u8::MAX as u16 => Usage::Register(a, a, Some(i as u8)),
+        Parameters::OneRegisterLongImmediate(a, i) => Usage::WideAddress(a, Ok(i)),
+        Parameters::OneRegisterExpression(a, ref expr) => Usage::WideAddress(a, Err(expr.clone())),
+    };
+    
+    // Swap A and B according to register map, the first operand after the swap goes in
+    // the low nibble and the second in the high nibble:
+    //   AA: both nibbles hold A, `clr r3` is 0x33
+    //   AB: A low, B high, `cmp r1, r2` is 0x21
+    //   BA: B low, A high, `add r1, r2` is 0x12
+    // A single register operand is passed as both A and B
+    let pack = |a: Register, b: Register| {
+        let (Register(a), Register(b)) = match asm_info.2 {
+            RegisterMap::AA => (a, a),
+            RegisterMap::AB => (a, b),
+            RegisterMap::BA => (b, a),
+        };
+        (a & 0x0F) | (b << 4 & 0xF0)
+    };
+    
+    match usage {
+        Usage::Register(a, b, maybe_i) => {
+            let mid = pack(a, b);
+            match maybe_i {
+                Some(i) => Encoded {bytes: vec![asm_info.0 | 0b10000000, mid, i], link: None},
+                None => Encoded {bytes: vec![asm_info.0, mid], link: None},
+            }
+        },
+        
+        // The immediate form with a full 16-bit value after the registers
+        Usage::WideImmediate(a, b, i) => {
+            let [first, second] = endian.bytes(i);
+            Encoded {bytes: vec![asm_info.0 | 0b10000000, pack(a, b), first, second], link: None}
+        },
+        
+        Usage::LongImmediate(i) => {
+            let [first, second] = endian.bytes(i);
+            Encoded {bytes: vec![asm_info.0 | 0b10000000, first, second], link: None}
+        },
+        
+        Usage::WideAddress(Register(a), address) => {
+            let opcode = name.wide_address().unwrap_or(asm_info.0);
+            let (address, link) = match address {
+                Ok(i) => (i, None),
+                Err(expr) => (0xADDE, Some((expr, 2, false))),
+            };
+            let [first, second] = endian.bytes(address);
+            Encoded {bytes: vec![opcode | 0b10000000, (a & 0x0F) | (a << 4 & 0xF0), first, second], link}
+        },
+        
+        // Support for labels, relative jumps are resolved against the start of the instruction
+        Usage::Unresolved(label) => Encoded {bytes: vec![asm_info.0 | 0b10000000, 0xDE, 0xAD], link: Some((label, 1, name.is_relative()))},
+    }
+}
+
+// What a reference to a label or expression resolves to
+enum Link {
+    Value(u16),
+    /// Holds the first symbol that isn't defined
+    Unresolved(String),
+    /// Holds why the value doesn't fit
+    Invalid(String),
+}
+
+// Names the closest defined label as well, when one is close enough to be a typo.
+// Shared by the buffered and streamed paths so the same source reports the same message
+fn unresolved_message<'a>(symbol: &str, labels: impl Iterator<Item = &'a String>) -> String {
+    // Numeric labels only have internal names, the label tables are sorted so ties are the same between runs
+    let labels = labels.map(String::as_str).filter(|name| !name.contains('@'));
+    match closest_name(symbol, labels) {
+        Some(close) => format!("unresolved symbol: {}, did you mean `{}`? [PENDING LINKER]", symbol, close),
+        None => format!("unresolved symbol: {} [PENDING LINKER]", symbol),
+    }
+}
+
+// Resolves a 16-bit link value, relative to `base` if given
+fn link_value(expr: &Expression, base: Option<usize>, lookup: &dyn Fn(&str) -> Option<i64>) -> Link {
+    let location = match expr.evaluate(lookup) {
+        Ok(location) => location,
+        Err(symbol) => return Link::Unresolved(symbol),
+    };
+    match base {
+        Some(base) => {
+            let delta = location - base as i64;
+            if delta < i16::MIN as i64 || delta > i16::MAX as i64 {
+                return Link::Invalid(format!("relative offset {} out of range ({}..{})", delta, i16::MIN, i16::MAX));
+            }
+            Link::Value(delta as i16 as u16)
+        },
+        None => {
+            if location < i16::MIN as i64 || location > u16::MAX as i64 {
+                return Link::Invalid(match expr {
+                    Expression::Symbol(label) => format!("label {} at 0x{:x} exceeds 16-bit address space", label, location),
+                    _ => format!("expression value {} does not fit in 16 bits", location),
+                });
+            }
+            Link::Value(location as u16)
+        },
+    }
+}
+
+/// Everything one assembly produces
+#[derive(Clone, Debug, Default)]
+pub struct AssembleResult {
+    pub bytes: Vec<u8>,
+    pub link_info: LinkInfo,
+    /// Final address of every label, for debuggers and emulators that map names to PC values.
+    /// Local labels are named `scope.local`
+    pub symbols: BTreeMap<String, u16>,
+    /// Labels given a fixed address with `name = 0x8000`, they are in `symbols` as well
+    pub absolute: BTreeSet<String>,
+    /// Which line every written byte came from, in source order. Gaps left by `.line` are not mapped
+    pub line_map: Vec<LineMapping>,
+    /// Name and address of the label set with `.entry`
+    pub entry: Option<(String, u16)>,
+    pub logs: Vec<Log>,
+}
+
+pub fn assemble_lines(lines: &[Line], options: &CodegenOptions) -> AssembleResult {
+    assemble_at(lines, options, 0)
+}
+
+/// Assembles into `buf`, which holds memory from address `base` on, for tools that flash in place.
+/// Labels resolve to `base` plus their offset and `.line` offsets count from `base`. The program is
+/// still built in memory first, the caller just doesn't get a `Vec` back. Returns how many bytes were
+/// written, or every log if there was an error or the program doesn't fit, in which case `buf` is untouched.
+pub fn assemble_into(lines: &[Line], options: &CodegenOptions, buf: &mut [u8], base: u16) -> Result<usize, Vec<Log>> {
+    let AssembleResult {bytes, line_map, mut logs, ..} = assemble_at(lines, options, base);
+    if bytes.len() > buf.len() {
+        // Blame the first line that writes past the end, a trailing `.line` may not write anything
+        let (line, origin) = line_map.iter().find(|m| m.range.end > buf.len()).or_else(|| line_map.last()).map_or((0, Arc::default()), |m| (m.line, m.origin.clone()));
+        logs.push(Log::Error(line, None, Code::AddressSpace, format!("program is {} bytes, the buffer only holds {}", bytes.len(), buf.len()), origin));
+    }
+    if logs.iter().any(Log::is_error) {
+        return Err(logs);
+    }
+    buf[..bytes.len()].copy_from_slice(&bytes);
+    Ok(bytes.len())
 }
 
-pub fn assemble_lines(lines: &[Line]) -> (Vec<u8>, Vec<Log>) {
+// Assembles as if the first byte is loaded at `base`
+fn assemble_at(lines: &[Line], options: &CodegenOptions, base: u16) -> AssembleResult {
+    let base = base as usize;
     let mut logs = Vec::new();
     
     let mut buffer = Vec::new();
-    let mut link_table = std::collections::HashMap::<String, usize>::new();
+    // Ordered so anything walking the labels, like suggestions for a typo, comes out the same every run
+    let mut link_table = BTreeMap::<String, LabelDefinition>::new();
     let mut unresolved = Vec::new();
+    let mut relocations = Vec::new();
+    let mut globals = Vec::new();
+    let mut absolute = BTreeSet::new();
+    let mut entry_point: Option<(&String, usize, Arc<String>)> = None;
+    let mut definitions = Vec::new();
+    let mut line_map = Vec::new();
+    let mut overflowed = false;
+    let mut warned_db_label = false;
+    // Where the `.db` bytes right before the current line start, labels between them don't count
+    let mut data_start = None;
+    
+    // Where the next byte goes, only differs from the buffer length after a backwards `.line`
+    let mut cursor = 0;
+    // Start, line and origin of the region being written, and the ranges of every finished region
+    let mut region = (0, 0, lines.first().map(|l| l.origin.clone()).unwrap_or_default());
+    let mut written: Vec<core::ops::Range<usize>> = Vec::new();
+    
+    macro_rules! emit {
+        ($byte:expr) => {{
+            let byte = $byte;
+            if cursor < buffer.len() {
+                buffer[cursor] = byte;
+            } else {
+                buffer.push(byte);
+            }
+            cursor += 1;
+        }}
+    }
+    // Finishes the current region, warning if it overwrote an earlier one
+    macro_rules! close_region {
+        () => {{
+            let current = region.0..cursor;
+            if !current.is_empty() {
+                for previous in written.iter().filter(|r| r.start < current.end && current.start < r.end) {
+                    logs.push(Log::Warning(region.1, None, Code::RegionOverlap, format!("region 0x{:x}..0x{:x} overlaps bytes already written at 0x{:x}..0x{:x}", current.start, current.end, previous.start, previous.end), region.2.clone()));
+                }
+                written.push(current);
+            }
+        }}
+    }
     
     for line in lines {
         let file_name = &line.origin;
+        let start = cursor;
         
         match &line.data {
-            // TODO: Create link table
-            LineData::Label(name) => {
-                if let Some(_overriden_label) = link_table.insert(name.clone(), buffer.len()) {
-                    logs.push(Log::Error(line.line, format!("symbol {} declared multiple times", name), file_name.clone()));
+            LineData::Label(name) | LineData::AbsoluteLabel(name, _) => {
+                let address = match line.data {
+                    LineData::AbsoluteLabel(_, address) => {
+                        absolute.insert(name.clone());
+                        address as usize
+                    },
+                    _ => base + cursor,
+                };
+                definitions.push((name, line.line, file_name.clone()));
+                match link_table.entry(name.clone()) {
+                    Entry::Occupied(previous) => {
+                        let previous = previous.get();
+                        let msg = format!("symbol {} declared multiple times, previously defined at {}:{}", name, previous.origin, previous.line + 1);
+                        logs.push(Log::Error(line.line, None, Code::DuplicateSymbol, msg, file_name.clone()));
+                    },
+                    Entry::Vacant(entry) => {
+                        entry.insert(LabelDefinition {address, line: line.line, origin: file_name.clone()});
+                    },
                 }
             },
             
             LineData::Directive(dir) => {
                 match dir {
                     Directive::Line(offset) => {
-                        if *offset < buffer.len() as u16 {
-                            logs.push(Log::Error(line.line, format!("line offset is less than current offset: {:x}", buffer.len()), file_name.clone()));
-                        } else {
-                            let padding = offset - buffer.len() as u16;
-                            if padding % 2 == 1 {
-                                logs.push(Log::Warning(line.line, "line offset will not guarantee instruction alignment".to_owned(), file_name.clone()));
-                            }
-                            buffer.resize(buffer.len() + padding as usize, 0);
+                        let offset = *offset as usize;
+                        if offset > cursor && (offset - cursor) % 2 == 1 {
+                            logs.push(Log::Warning(line.line, None, Code::Alignment, "line offset will not guarantee instruction alignment".to_owned(), file_name.clone()));
+                        }
+                        // Usually a copy-pasted offset that was meant to be changed
+                        if offset == buffer.len() {
+                            logs.push(Log::Warning(line.line, None, Code::EmptyPadding, "line directive produced no padding".to_owned(), file_name.clone()));
+                        }
+                        close_region!();
+                        // Moving backwards is allowed as long as the new region only fills padding
+                        cursor = offset;
+                        region = (offset, line.line, file_name.clone());
+                        if buffer.len() < offset {
+                            buffer.resize(offset, options.fill);
                         }
                     },
                     
                     Directive::DB(data_byte) => {
+                        data_start.get_or_insert(cursor);
                         for db in data_byte {
                             match db {
-                                DataByte::Byte(byte) => buffer.push(*byte),
+                                DataByte::Byte(byte) => emit!(*byte),
                                 DataByte::Label(label) => {
-                                    unresolved.push((label.clone(), buffer.len(), line.line, file_name.clone()));
-                                    buffer.push(0xDE);
-                                    buffer.push(0xAD);
-                                }
+                                    // Once is enough to explain it, every label after that takes two bytes as well
+                                    if !warned_db_label {
+                                        warned_db_label = true;
+                                        logs.push(Log::Warning(line.line, None, Code::DbLabelWidth, "label in .db occupies 2 bytes (full address); use low()/high() for a single byte".to_owned(), file_name.clone()));
+                                    }
+                                    unresolved.push((Expression::Symbol(label.clone()), cursor, None, line.line, file_name.clone(), None));
+                                    emit!(0xDE);
+                                    emit!(0xAD);
+                                },
+                                DataByte::Expression(expr) => {
+                                    unresolved.push((expr.clone(), cursor, None, line.line, file_name.clone(), None));
+                                    emit!(0xDE);
+                                    emit!(0xAD);
+                                },
+                                DataByte::LabelByte(label, select) => {
+                                    unresolved.push((Expression::Symbol(label.clone()), cursor, None, line.line, file_name.clone(), Some(*select)));
+                                    emit!(0xDE);
+                                },
                             }
                         }
-                    }
+                    },
+                    
+                    Directive::Global(name) => globals.push((name, line.line, file_name.clone())),
+                    
+                    Directive::Entry(name) => match &entry_point {
+                        Some((_, previous, origin)) => {
+                            let msg = format!("entry point declared multiple times, previously set at {}:{}", origin, previous + 1);
+                            logs.push(Log::Error(line.line, None, Code::DuplicateSymbol, msg, file_name.clone()));
+                        },
+                        None => entry_point = Some((name, line.line, file_name.clone())),
+                    },
                 }
             }
             
+            LineData::Pseudo(..) => {
+                logs.push(Log::Error(line.line, None, Code::Syntax, "pseudo-instruction was not expanded".to_owned(), file_name.clone()));
+            },
+            
             LineData::Instruction {name, params} => {
-                let asm_info = name.assemble_info();
+                // Lines built by hand can pair an instruction with operands it has no encoding for
+                if !accepts(name.operand_mode(), params) {
+                    logs.push(Log::Error(line.line, None, Code::Syntax, format!("{} can not take operands {:?}", name.to_str(), params), file_name.clone()));
+                    continue;
+                }
+                // Still encoded so the labels after it don't move
+                if !name.available_on(options.cpu) {
+                    logs.push(Log::Error(line.line, None, Code::UnknownInstruction, format!("{} not available on cpu {}", name.to_str(), options.cpu.name()), file_name.clone()));
+                }
                 
-                enum Usage {
-                    Register(Register, Register, Option<u8>),
-                    LongImmidiate(u16),
-                    Unresolved(String),
+                // Instructions are laid out even, data in front of code is the usual way to break that
+                if let Some(data_start) = data_start {
+                    if options.warn_align && (start - data_start) % 2 == 1 {
+                        let msg = format!("instruction at 0x{:x} follows {} bytes of .db, an odd count leaves it misaligned", start, start - data_start);
+                        logs.push(Log::Warning(line.line, None, Code::Alignment, msg, file_name.clone()));
+                    }
                 }
                 
-                let usage: Usage = match *params {
-                    Parameters::None => Usage::Register(Register(0), Register(0), None),
-                    Parameters::Label(ref label) => Usage::Unresolved(label.clone()),
-                    Parameters::OneRegister(a) => Usage::Register(a, a, None),
-                    Parameters::LongImmediate(i) => Usage::LongImmidiate(i),
-                    Parameters::TwoRegisters(a, b) => Usage::Register(a, b, None),
-                    Parameters::OneRegisterImmediate(a, i) => Usage::Register(a, a, Some(i)),
-                    Parameters::TwoRegistersImmedaite(a, b, i) => Usage::Register(a, b, Some(i)),
+                let encoded = encode(*name, params, options.endian);
+                if let Some((expr, at, relative)) = encoded.link {
+                    unresolved.push((expr, start + at, if relative { Some(start) } else { None }, line.line, file_name.clone(), None));
+                }
+                for byte in &encoded.bytes {
+                    emit!(*byte);
+                }
+            }
+        }
+        
+        if !matches!(line.data, LineData::Label(_) | LineData::AbsoluteLabel(..) | LineData::Directive(Directive::DB(_) | Directive::Global(_) | Directive::Entry(_))) {
+            data_start = None;
+        }
+        
+        // `.line` moves the cursor without writing anything
+        if cursor > start && !matches!(line.data, LineData::Directive(Directive::Line(_))) {
+            line_map.push(LineMapping {range: start..cursor, origin: file_name.clone(), line: line.line});
+        }
+        
+        // Only report the first line that pushes the program out of the address space
+        if !overflowed && base + buffer.len() > 0x10000 {
+            overflowed = true;
+            logs.push(Log::Error(line.line, None, Code::AddressSpace, format!("program size 0x{:x} exceeds 16-bit address space", buffer.len()), file_name.clone()));
+        }
+    }
+    close_region!();
+    
+    let mut used = HashSet::new();
+    used.extend(entry_point.iter().map(|(name, ..)| (*name).clone()));
+    for link in &unresolved {
+        used.extend(link.0.symbols().into_iter().map(String::from));
+    }
+    
+    for link in unresolved {
+        let value = match link_value(&link.0, link.2.map(|start| base + start), &|name| link_table.get(name).map(|label| label.address as i64)) {
+            Link::Value(value) => value,
+            Link::Invalid(msg) => {
+                logs.push(Log::Error(link.3, None, Code::AddressSpace, msg, link.4.clone()));
+                continue;
+            },
+            // Only a bare symbol can be patched by the linker, there is nowhere to store the rest
+            Link::Unresolved(symbol) if options.relocatable => {
+                match &link.0 {
+                    Expression::Symbol(_) if link.5.is_some() => logs.push(Log::Error(link.3, None, Code::UnresolvedSymbol, format!("a single byte of external symbol {} can not be relocated", symbol), link.4.clone())),
+                    Expression::Symbol(_) => relocations.push(Relocation {
+                        symbol,
+                        offset: link.1 as u16,
+                        width: 2,
+                        relative: link.2.is_some(),
+                    }),
+                    _ => logs.push(Log::Error(link.3, None, Code::UnresolvedSymbol, format!("expression using external symbol {} can not be relocated", symbol), link.4.clone())),
+                }
+                continue;
+            },
+            Link::Unresolved(symbol) => {
+                logs.push(Log::Error(link.3, None, Code::UnresolvedSymbol, unresolved_message(&symbol, link_table.keys()), link.4.clone()));
+                continue;
+            },
+        };
+        match link.5 {
+            Some(ByteSelect::Low) => buffer[link.1] = (value & 0xFF) as u8,
+            Some(ByteSelect::High) => buffer[link.1] = (value >> 8) as u8,
+            None => buffer[link.1..link.1 + 2].copy_from_slice(&options.endian.bytes(value)),
+        }
+    }
+    
+    // Labels past the address space have already been reported above
+    let mut symbols = Vec::new();
+    for (name, line, origin) in globals {
+        match link_table.get(name) {
+            Some(label) => symbols.push((name.clone(), label.address as u16)),
+            None => logs.push(Log::Error(line, None, Code::UnresolvedSymbol, format!("global symbol {} is never defined", name), origin)),
+        }
+    }
+    
+    let entry = entry_point.and_then(|(name, line, origin)| match link_table.get(name) {
+        Some(label) => Some((name.clone(), label.address as u16)),
+        None => {
+            logs.push(Log::Error(line, None, Code::UnresolvedSymbol, format!("entry point {} is never defined", name), origin));
+            None
+        },
+    });
+    
+    if options.warn_unused_labels {
+        // Numeric labels are meant to be throwaway
+        for (name, line, origin) in definitions {
+            if !used.contains(name) && !name.contains('@') && !symbols.iter().any(|(global, _)| global == name) {
+                logs.push(Log::Warning(line, None, Code::UnusedLabel, format!("label {} is never used", name), origin));
+            }
+        }
+    }
+    
+    logs.retain(|log| log.is_error() || !options.allow.contains(&log.code()));
+    if options.warnings_as_errors {
+        logs = logs.into_iter().map(Log::into_error).collect();
+    }
+    
+    AssembleResult {
+        bytes: buffer,
+        link_info: LinkInfo {globals: symbols, relocations},
+        symbols: link_table.into_iter().map(|(name, label)| (name, label.address as u16)).collect(),
+        absolute,
+        line_map,
+        entry,
+        logs,
+    }
+}
+
+/// Assembles straight into `writer` instead of building the whole program in memory.
+///
+/// The first pass only measures each line to place the labels, the second encodes every line again and
+/// writes it out with label values already patched in. Memory use is bounded by the label table instead of
+/// the program size, at the cost of encoding everything twice. Diagnostics match [`assemble_lines`] except
+/// where written bytes can't be revisited: `.line` may only move forwards, so there are no overlap warnings,
+/// and undefined symbols are always errors since there are no relocations. Lines with errors are left out
+/// of the output, which should be discarded if any error is logged.
+#[cfg(feature = "std")]
+pub fn assemble_to_writer<W: Write>(lines: &[Line], options: &CodegenOptions, writer: &mut W) -> io::Result<Vec<Log>> {
+    let mut logs = Vec::new();
+    
+    // Pass one: where every label points
+    let mut labels = BTreeMap::<String, LabelDefinition>::new();
+    let mut globals = Vec::new();
+    let mut entry_point: Option<(&String, usize, Arc<String>)> = None;
+    let mut definitions = Vec::new();
+    let mut used = HashSet::new();
+    let mut cursor = 0;
+    let mut overflowed = false;
+    let mut warned_db_label = false;
+    let mut data_start = None;
+    for line in lines {
+        let start = cursor;
+        match &line.data {
+            LineData::Label(name) | LineData::AbsoluteLabel(name, _) => {
+                let address = match line.data {
+                    LineData::AbsoluteLabel(_, address) => address as usize,
+                    _ => cursor,
                 };
-                
-                match usage {
-                    Usage::Register(a, b, maybe_i) => {
-                        // Swap A and B according to register map
-                        let (Register(a), Register(b)) = match asm_info.2 {
-                            RegisterMap::AA => (a, a),
-                            RegisterMap::AB => (a, b),
-                            RegisterMap::BA => (b, a),
-                        };
-                        let mid = (a & 0x0F) | (b << 4 & 0xF0);
-                        if let Some(i) = maybe_i {
-                            buffer.push(asm_info.0 | 0b10000000);
-                            buffer.push(mid);
-                            buffer.push(i);
-                        } else {
-                            buffer.push(asm_info.0);
-                            buffer.push(mid);
-                        }
+                definitions.push((name, line.line, line.origin.clone()));
+                match labels.entry(name.clone()) {
+                    Entry::Occupied(previous) => {
+                        let previous = previous.get();
+                        let msg = format!("symbol {} declared multiple times, previously defined at {}:{}", name, previous.origin, previous.line + 1);
+                        logs.push(Log::Error(line.line, None, Code::DuplicateSymbol, msg, line.origin.clone()));
                     },
-                    
-                    Usage::LongImmidiate(i) => {
-                        buffer.push(asm_info.0 | 0b10000000);
-                        buffer.push((i & 0xFF) as u8);
-                        buffer.push((i >> 8) as u8);
+                    Entry::Vacant(entry) => {
+                        entry.insert(LabelDefinition {address, line: line.line, origin: line.origin.clone()});
                     },
-                    
-                    // Support for labels
-                    Usage::Unresolved(label) => {
-                        buffer.push(asm_info.0 | 0b10000000);
-                        // Temporary data
-                        unresolved.push((label, buffer.len(), line.line, file_name.clone()));
-                        buffer.push(0xDE);
-                        buffer.push(0xAD);
-                    },
-                };
+                }
+            },
+            LineData::Directive(Directive::Line(offset)) => {
+                let offset = *offset as usize;
+                if offset < cursor {
+                    logs.push(Log::Error(line.line, None, Code::AddressSpace, format!("line offset 0x{:x} moves backwards from 0x{:x}, which can not be streamed", offset, cursor), line.origin.clone()));
+                } else {
+                    if (offset - cursor) % 2 == 1 {
+                        logs.push(Log::Warning(line.line, None, Code::Alignment, "line offset will not guarantee instruction alignment".to_owned(), line.origin.clone()));
+                    }
+                    if offset == cursor {
+                        logs.push(Log::Warning(line.line, None, Code::EmptyPadding, "line directive produced no padding".to_owned(), line.origin.clone()));
+                    }
+                    cursor = offset;
+                }
+            },
+            LineData::Directive(Directive::DB(data_byte)) => {
+                data_start.get_or_insert(cursor);
+                if !warned_db_label && data_byte.iter().any(|db| matches!(db, DataByte::Label(_))) {
+                    warned_db_label = true;
+                    logs.push(Log::Warning(line.line, None, Code::DbLabelWidth, "label in .db occupies 2 bytes (full address); use low()/high() for a single byte".to_owned(), line.origin.clone()));
+                }
+                for db in data_byte {
+                    match db {
+                        DataByte::Byte(_) => cursor += 1,
+                        DataByte::LabelByte(label, _) => {
+                            used.insert(label.clone());
+                            cursor += 1;
+                        },
+                        DataByte::Label(label) => {
+                            used.insert(label.clone());
+                            cursor += 2;
+                        },
+                        DataByte::Expression(expr) => {
+                            used.extend(expr.symbols().into_iter().map(String::from));
+                            cursor += 2;
+                        },
+                    }
+                }
+            },
+            LineData::Directive(Directive::Global(name)) => globals.push((name, line.line, line.origin.clone())),
+            LineData::Directive(Directive::Entry(name)) => match &entry_point {
+                Some((_, previous, origin)) => {
+                    let msg = format!("entry point declared multiple times, previously set at {}:{}", origin, previous + 1);
+                    logs.push(Log::Error(line.line, None, Code::DuplicateSymbol, msg, line.origin.clone()));
+                },
+                None => entry_point = Some((name, line.line, line.origin.clone())),
+            },
+            LineData::Pseudo(..) => {
+                logs.push(Log::Error(line.line, None, Code::Syntax, "pseudo-instruction was not expanded".to_owned(), line.origin.clone()));
+            },
+            LineData::Instruction {name, params} => {
+                if !accepts(name.operand_mode(), params) {
+                    logs.push(Log::Error(line.line, None, Code::Syntax, format!("{} can not take operands {:?}", name.to_str(), params), line.origin.clone()));
+                    continue;
+                }
+                if !name.available_on(options.cpu) {
+                    logs.push(Log::Error(line.line, None, Code::UnknownInstruction, format!("{} not available on cpu {}", name.to_str(), options.cpu.name()), line.origin.clone()));
+                }
+                if let Some(data_start) = data_start {
+                    if options.warn_align && (start - data_start) % 2 == 1 {
+                        let msg = format!("instruction at 0x{:x} follows {} bytes of .db, an odd count leaves it misaligned", start, start - data_start);
+                        logs.push(Log::Warning(line.line, None, Code::Alignment, msg, line.origin.clone()));
+                    }
+                }
+                let encoded = encode(*name, params, options.endian);
+                if let Some((expr, ..)) = &encoded.link {
+                    used.extend(expr.symbols().into_iter().map(String::from));
+                }
+                cursor += encoded.bytes.len();
+            },
+        }
+        
+        if !matches!(line.data, LineData::Label(_) | LineData::AbsoluteLabel(..) | LineData::Directive(Directive::DB(_) | Directive::Global(_) | Directive::Entry(_))) {
+            data_start = None;
+        }
+        
+        if !overflowed && cursor > 0x10000 {
+            overflowed = true;
+            logs.push(Log::Error(line.line, None, Code::AddressSpace, format!("program size 0x{:x} exceeds 16-bit address space", cursor), line.origin.clone()));
+        }
+    }
+    used.extend(entry_point.iter().map(|(name, ..)| (*name).clone()));
+    
+    // Pass two: encode again and write
+    let lookup = |name: &str| labels.get(name).map(|label| label.address as i64);
+    let mut cursor = 0;
+    for line in lines {
+        // Patches a link into `bytes`, logging if it can't be resolved
+        let mut link = |bytes: &mut [u8], expr: &Expression, base: Option<usize>| {
+            let value = match link_value(expr, base, &lookup) {
+                Link::Value(value) => value,
+                Link::Invalid(msg) => {
+                    logs.push(Log::Error(line.line, None, Code::AddressSpace, msg, line.origin.clone()));
+                    return;
+                },
+                Link::Unresolved(symbol) => {
+                    logs.push(Log::Error(line.line, None, Code::UnresolvedSymbol, unresolved_message(&symbol, labels.keys()), line.origin.clone()));
+                    return;
+                },
+            };
+            bytes.copy_from_slice(&options.endian.bytes(value));
+        };
+        
+        match &line.data {
+            LineData::Directive(Directive::Line(offset)) if *offset as usize > cursor => {
+                let offset = *offset as usize;
+                writer.write_all(&vec![options.fill; offset - cursor])?;
+                cursor = offset;
+            },
+            LineData::Directive(Directive::DB(data_byte)) => {
+                for db in data_byte {
+                    let mut bytes = [0xDE, 0xAD];
+                    let bytes = match db {
+                        DataByte::Byte(byte) => core::slice::from_ref(byte),
+                        DataByte::Label(label) => {
+                            link(&mut bytes, &Expression::Symbol(label.clone()), None);
+                            &bytes[..]
+                        },
+                        DataByte::Expression(expr) => {
+                            link(&mut bytes, expr, None);
+                            &bytes[..]
+                        },
+                        DataByte::LabelByte(label, select) => {
+                            link(&mut bytes, &Expression::Symbol(label.clone()), None);
+                            // The low byte only comes first in little endian
+                            match (select, options.endian) {
+                                (ByteSelect::Low, Endian::Little) | (ByteSelect::High, Endian::Big) => &bytes[..1],
+                                _ => &bytes[1..],
+                            }
+                        },
+                    };
+                    writer.write_all(bytes)?;
+                    cursor += bytes.len();
+                }
+            },
+            LineData::Instruction {name, params} if accepts(name.operand_mode(), params) => {
+                let mut encoded = encode(*name, params, options.endian);
+                if let Some((expr, at, relative)) = &encoded.link {
+                    link(&mut encoded.bytes[*at..*at + 2], expr, if *relative { Some(cursor) } else { None });
+                }
+                writer.write_all(&encoded.bytes)?;
+                cursor += encoded.bytes.len();
+            },
+            _ => {},
+        }
+    }
+    
+    for (name, line, origin) in &globals {
+        if !labels.contains_key(*name) {
+            logs.push(Log::Error(*line, None, Code::UnresolvedSymbol, format!("global symbol {} is never defined", name), origin.clone()));
+        }
+    }
+    if let Some((name, line, origin)) = entry_point {
+        if !labels.contains_key(name) {
+            logs.push(Log::Error(line, None, Code::UnresolvedSymbol, format!("entry point {} is never defined", name), origin));
+        }
+    }
+    if options.warn_unused_labels {
+        for (name, line, origin) in definitions {
+            if !used.contains(name) && !name.contains('@') && !globals.iter().any(|(global, ..)| *global == name) {
+                logs.push(Log::Warning(line, None, Code::UnusedLabel, format!("label {} is never used", name), origin));
             }
         }
     }
     
-    for link in unresolved {
-        if let Some(location) = link_table.get(&link.0) {
-            let offset = *location as u16;
-            let lo = (offset & 0xFF) as u8;
-            let hi = (offset >> 8) as u8;
-            buffer[link.1] = lo;
-            buffer[link.1 + 1] = hi;
-        } else {
-            // TODO: linker!
-            logs.push(Log::Error(link.2, format!("unresolved symbol: {} [PENDING LINKER]", link.0), link.3.clone()));
+    logs.retain(|log| log.is_error() || !options.allow.contains(&log.code()));
+    if options.warnings_as_errors {
+        logs = logs.into_iter().map(Log::into_error).collect();
+    }
+    
+    Ok(logs)
+}
+
+// Decodes one instruction at the start of `bytes`, None if it isn't a valid encoding of `ins`
+fn decode(ins: Instruction, bytes: &[u8]) -> Option<(String, usize)> {
+    let name = ins.to_str();
+    let immediate = bytes[0] & 0b10000000 != 0;
+    let wide = immediate && ins.wide_address() == Some(bytes[0] & 0b01111111);
+    let width = match (ins.operand_mode(), immediate) {
+        (OperandMode::OneRegisterAndLongImmediate, true) if wide => 4,
+        (OperandMode::TwoRegistersAndLongImmediate, true) => 4,
+        (OperandMode::NoParams, false) | (OperandMode::OneRegister, false) |
+        (OperandMode::OneOrTwoRegisters, false) | (OperandMode::TwoRegisters, false) |
+        (OperandMode::TwoRegistersOrImmediate, false) | (OperandMode::TwoRegistersOrLongImmediate, false) => 2,
+        (OperandMode::OneRegisterAndImmediate, true) | (OperandMode::OneRegisterAndLongImmediate, true) |
+        (OperandMode::TwoRegistersOrImmediate, true) | (OperandMode::TwoRegistersOrLongImmediate, true) => 3,
+        _ => return None,
+    };
+    let bytes = bytes.get(..width)?;
+    
+    // Undo the register map swap, see the packing in `assemble_lines`
+    let (lo, hi) = (bytes[1] & 0x0F, bytes[1] >> 4);
+    let (a, b) = match ins.register_map() {
+        RegisterMap::AB | RegisterMap::AA => (lo, hi),
+        RegisterMap::BA => (hi, lo),
+    };
+    let text = match (ins.operand_mode(), width) {
+        (OperandMode::NoParams, _) => name.to_owned(),
+        (OperandMode::OneRegister, _) => format!("{} r{}", name, a),
+        (OperandMode::OneOrTwoRegisters, _) | (OperandMode::TwoRegistersOrImmediate, 2) if a == b => format!("{} r{}", name, a),
+        (OperandMode::TwoRegistersOrImmediate, 3) if a == b => format!("{} r{}, 0x{:02X}", name, a, bytes[2]),
+        (OperandMode::TwoRegistersOrImmediate, 3) => format!("{} r{}, r{}, 0x{:02X}", name, a, b, bytes[2]),
+        (OperandMode::OneRegisterAndImmediate, _) | (OperandMode::OneRegisterAndLongImmediate, 3) => format!("{} r{}, 0x{:02X}", name, a, bytes[2]),
+        (OperandMode::OneRegisterAndLongImmediate, _) => format!("{} r{}, 0x{:04X}", name, a, u16::from_le_bytes([bytes[2], bytes[3]])),
+        (OperandMode::TwoRegistersAndLongImmediate, _) => format!("{} r{}, r{}, 0x{:04X}", name, a, b, u16::from_le_bytes([bytes[2], bytes[3]])),
+        (OperandMode::TwoRegistersOrLongImmediate, 3) => format!("{} 0x{:04X}", name, u16::from_le_bytes([bytes[1], bytes[2]])),
+        _ => format!("{} r{}, r{}", name, a, b),
+    };
+    Some((text, width))
+}
+
+/// Decodes assembled bytes back into one line of source per instruction.
+/// Bytes that don't start a valid instruction are written as `.db 0xNN`.
+pub fn disassemble(bytes: &[u8]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut position = 0;
+    while position < bytes.len() {
+        let rest = &bytes[position..];
+        let opcode = rest[0] & 0b01111111;
+        let decoded = Instruction::all().iter()
+            .filter(|ins| ins.opcode() == opcode || ins.wide_address() == Some(opcode))
+            .find_map(|ins| decode(*ins, rest));
+        let (text, width) = decoded.unwrap_or_else(|| (format!(".db 0x{:02X}", rest[0]), 1));
+        lines.push(text);
+        position += width;
+    }
+    lines
+}
+
+/// CRC-16/CCITT-FALSE (polynomial 0x1021, initial value 0xFFFF) of the assembled bytes
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { crc << 1 ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Offset, hex bytes in pairs and printable ASCII, 16 bytes to a line like `xxd`
+pub fn hexdump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, row) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = row.chunks(2).map(|pair| pair.iter().map(|b| format!("{:02x}", b)).collect()).collect();
+        let ascii: String = row.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+        dump += &format!("{:08x}: {:<39}  {}\n", i * 16, hex.join(" "), ascii);
+    }
+    dump
+}
+
+/// Magic bytes at the start of every object file
+pub const OBJECT_MAGIC: [u8; 4] = *b"X69O";
+pub const OBJECT_VERSION: u8 = 1;
+
+/// Writes code along with its link info as an object file.
+/// All integers are little endian and strings are a u16 length followed by UTF-8 bytes.
+///
+/// | Field         | Size                                                     |
+/// |---------------|----------------------------------------------------------|
+/// | magic         | 4, `X69O`                                                |
+/// | version       | 1                                                        |
+/// | symbol count  | 2                                                        |
+/// | reloc count   | 2                                                        |
+/// | code length   | 4                                                        |
+/// | symbols       | name, address (2)                                        |
+/// | relocations   | name, offset (2), width (1), flags (1, bit 0 = relative) |
+/// | code          | code length                                              |
+#[cfg(feature = "std")]
+pub fn write_object<W: Write>(writer: &mut W, code: &[u8], link_info: &LinkInfo) -> io::Result<()> {
+    fn write_name<W: Write>(writer: &mut W, name: &str) -> io::Result<()> {
+        writer.write_all(&(name.len() as u16).to_le_bytes())?;
+        writer.write_all(name.as_bytes())
+    }
+    
+    writer.write_all(&OBJECT_MAGIC)?;
+    writer.write_all(&[OBJECT_VERSION])?;
+    writer.write_all(&(link_info.globals.len() as u16).to_le_bytes())?;
+    writer.write_all(&(link_info.relocations.len() as u16).to_le_bytes())?;
+    writer.write_all(&(code.len() as u32).to_le_bytes())?;
+    
+    for (name, address) in &link_info.globals {
+        write_name(writer, name)?;
+        writer.write_all(&address.to_le_bytes())?;
+    }
+    for relocation in &link_info.relocations {
+        write_name(writer, &relocation.symbol)?;
+        writer.write_all(&relocation.offset.to_le_bytes())?;
+        writer.write_all(&[relocation.width, relocation.relative as u8])?;
+    }
+    
+    writer.write_all(code)
+}
+
+/// Magic bytes at the start of every debug line file
+pub const LINE_MAP_MAGIC: [u8; 4] = *b"X69D";
+pub const LINE_MAP_VERSION: u8 = 1;
+
+/// Writes the line map from `AssembleResult::line_map` for debuggers, in the same encoding as object files.
+/// Every origin is written once and entries refer to it by index, addresses missing from the table are padding.
+///
+/// | Field         | Size                                                        |
+/// |---------------|-------------------------------------------------------------|
+/// | magic         | 4, `X69D`                                                   |
+/// | version       | 1                                                           |
+/// | file count    | 2                                                           |
+/// | entry count   | 4                                                           |
+/// | files         | name                                                        |
+/// | entries       | start (2), length (2), file index (2), line (4, one based)   |
+#[cfg(feature = "std")]
+pub fn write_line_map<W: Write>(writer: &mut W, line_map: &[LineMapping]) -> io::Result<()> {
+    let mut files: Vec<&Arc<String>> = Vec::new();
+    for mapping in line_map {
+        if !files.contains(&&mapping.origin) {
+            files.push(&mapping.origin);
         }
     }
     
-    (buffer, logs)
+    writer.write_all(&LINE_MAP_MAGIC)?;
+    writer.write_all(&[LINE_MAP_VERSION])?;
+    writer.write_all(&(files.len() as u16).to_le_bytes())?;
+    writer.write_all(&(line_map.len() as u32).to_le_bytes())?;
+    for file in &files {
+        writer.write_all(&(file.len() as u16).to_le_bytes())?;
+        writer.write_all(file.as_bytes())?;
+    }
+    for mapping in line_map {
+        let file = files.iter().position(|file| **file == mapping.origin).unwrap_or_default();
+        writer.write_all(&(mapping.range.start as u16).to_le_bytes())?;
+        writer.write_all(&(mapping.range.len() as u16).to_le_bytes())?;
+        writer.write_all(&(file as u16).to_le_bytes())?;
+        writer.write_all(&(mapping.line as u32 + 1).to_le_bytes())?;
+    }
+    Ok(())
 }
 
-#[cfg(test)]
+/// Writes a text symbol map for emulators and debuggers, one `0xADDR name` line per label sorted by name.
+/// The entry point comes first as `entry 0xADDR name`, numeric labels are left out and labels with a
+/// fixed address end in `absolute` since they don't point into the program.
+#[cfg(feature = "std")]
+pub fn write_map<W: Write>(writer: &mut W, symbols: &BTreeMap<String, u16>, absolute: &BTreeSet<String>, entry: Option<&(String, u16)>) -> io::Result<()> {
+    if let Some((name, address)) = entry {
+        writeln!(writer, "entry 0x{:04X} {}", address, name)?;
+    }
+    for (name, address) in symbols.iter().filter(|(name, _)| !name.contains('@')) {
+        match absolute.contains(name) {
+            true => writeln!(writer, "0x{:04X} {} absolute", address, name)?,
+            false => writeln!(writer, "0x{:04X} {}", address, name)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "std"))]
 mod tests {
-    use crate::parser::parse_raw;
-    use crate::codegen::assemble_lines;
+    use crate::instruction::Cpu;
+    use crate::parser::{Code, Directive, LineData, Log, ParseOptions, parse_file, parse_raw};
+    use crate::codegen::{CodegenOptions, Endian, LINE_MAP_MAGIC, OBJECT_MAGIC, OBJECT_VERSION, AssembleResult, Relocation, assemble_lines, assemble_to_writer, crc16, disassemble, hexdump, write_line_map, write_map, write_object};
+    fn assemble_logs(source: &str) -> Vec<Log> {
+        let (lines, mut logs) = parse_raw(source, None);
+        logs.extend(assemble_lines(&lines, &CodegenOptions::default()).logs);
+        logs
+    }
     fn assemble_string(source: &str) -> Vec<u8> {
         let (lines, parse_logs) = parse_raw(source, None);
-        let (assembly, asm_logs) = assemble_lines(&lines);
+        let AssembleResult {bytes: assembly, logs: asm_logs, ..} = assemble_lines(&lines, &CodegenOptions::default());
         
         // Print out for debugging purposes
         parse_logs.iter().for_each(|log| println!("{}", log));
@@ -174,6 +1004,153 @@ mod tests {
         assert_eq!(buffer[1], 0x00);
     }
     
+    #[test]
+    fn ret() {
+        let buffer = assemble_string("ret");
+        assert_eq!(buffer, vec![0b01010000, 0x00]);
+        assert!(assemble_logs("ret r1").iter().any(|l| l.is_error()));
+    }
+    
+    #[test]
+    fn push_pop() {
+        assert_eq!(assemble_string("push r3"), vec![0b01010011, 0x33]);
+        assert_eq!(assemble_string("pop r3"), vec![0b01010100, 0x33]);
+    }
+    
+    #[test]
+    fn load_immediate() {
+        assert_eq!(assemble_string("li r3, 0x1234"), assemble_string("set r3, 0x34\nset r4, 0x12"));
+        assert_eq!(assemble_string("LI r3, 0x56"), assemble_string("set r3, 0x56\nclr r4"));
+        assert!(assemble_logs("li r15, 5").iter().any(|l| l.is_error()));
+    }
+    
+    #[test]
+    fn statements() {
+        assert_eq!(assemble_string("clr r0; clr r1"), assemble_string("clr r0\nclr r1"));
+        assert_eq!(assemble_string("nop;nop;"), assemble_string("nop\nnop"));
+    }
+    
+    #[test]
+    fn expressions() {
+        assert_eq!(assemble_string("set r0, 0x10+2"), assemble_string("set r0, 0x12"));
+        assert_eq!(assemble_string("set r0, 1<<4"), assemble_string("set r0, 16"));
+        assert_eq!(assemble_string("add r1, r2, (3+1)*2"), assemble_string("add r1, r2, 8"));
+        assert_eq!(assemble_string("jmp 0x100-2"), assemble_string("jmp 0xFE"));
+        assert_eq!(assemble_string(".line 2*2\nnop"), assemble_string(".line 4\nnop"));
+        assert_eq!(assemble_string(".db 1+1 -1"), vec![1]);
+        
+        // Symbols are resolved during codegen
+        assert_eq!(assemble_string("nop\nnop\nlabel: jmp label-2"), assemble_string("nop\nnop\njmp 2"));
+        assert_eq!(assemble_string("jmp end+1\nend:"), assemble_string("jmp 4"));
+        assert_eq!(assemble_string("nop\ndata: .db data+1"), vec![0b00101001, 0, 3, 0]);
+        
+        assert!(assemble_logs("set r0, label+1").iter().any(|l| l.is_error()));
+        assert!(assemble_logs("jmp missing+1").iter().any(|l| l.to_string().contains("unresolved symbol: missing")));
+    }
+    
+    #[test]
+    fn label_offset() {
+        let buffer = assemble_string("
+            nop
+        loop:
+            nop
+            jmp loop+2");
+        assert_eq!(buffer[5], 4);
+        assert_eq!(buffer[6], 0);
+        
+        assert_eq!(assemble_string("array: .db 7 array+1 array-0"), vec![7, 1, 0, 0, 0]);
+    }
+    
+    #[test]
+    fn macros() {
+        let buffer = assemble_string("
+        .macro clear_both
+            clr r0
+            clr r1
+        .endm
+            clear_both
+        start: clear_both
+            jmp start");
+        assert_eq!(buffer, assemble_string("clr r0\nclr r1\nclr r0\nclr r1\njmp 4"));
+        
+        let buffer = assemble_string("
+        .macro add_twice reg, value
+            add reg, value
+            add reg, value
+        .endm
+        .macro add_four reg
+            add_twice reg, 2; add_twice reg, 2
+        .endm
+            add_four r3");
+        assert_eq!(buffer, assemble_string("add r3, 2\nadd r3, 2\nadd r3, 2\nadd r3, 2"));
+    }
+    
+    #[test]
+    fn repeat() {
+        assert_eq!(assemble_string(".repeat 3\nnop\n.endr"), assemble_string("nop\nnop\nnop"));
+        assert_eq!(assemble_string(".repeat 0\nnop\n.endr"), Vec::<u8>::new());
+        assert_eq!(assemble_string(".repeat 4; .db (REPEAT_INDEX * 2); .endr"), vec![0, 2, 4, 6]);
+        
+        // Inner blocks see their own index, the outer index can be used for the count
+        let buffer = assemble_string("
+        .repeat 3
+            .repeat REPEAT_INDEX
+                .db REPEAT_INDEX
+            .endr
+            .db 0xFF
+        .endr");
+        assert_eq!(buffer, vec![0xFF, 0, 0xFF, 0, 1, 0xFF]);
+        
+        let buffer = assemble_string("
+        .macro fill count, value
+            .repeat count
+                .db value
+            .endr
+        .endm
+            fill 2, 7");
+        assert_eq!(buffer, vec![7, 7]);
+    }
+    
+    #[test]
+    fn register_packing() {
+        // One case for every register map and operand mode in use
+        let cases = [
+            ("nop", 0x00),
+            ("dnfg", 0x00),
+            ("clr r3", 0x33),
+            ("push r3", 0x33),
+            ("not r1", 0x11),
+            ("not r1, r2", 0x12),
+            ("set r5, 1", 0x55),
+            ("ldr r5, 1", 0x55),
+            ("ldr r5, 0x100", 0x55),
+            ("cmp r1, r2", 0x21),
+            ("lpc r1, r2", 0x21),
+            ("add r1, r2", 0x12),
+            ("add r1, 5", 0x11),
+            ("add r1, r2, 5", 0x12),
+            ("jmp r1, r2", 0x21),
+        ];
+        for (source, mid) in cases.iter() {
+            assert_eq!(assemble_string(source)[1], *mid, "{}", source);
+        }
+    }
+    
+    #[test]
+    fn alu_immediate_forms() {
+        // `add r1, 5` is short for `add r1, r1, 5`, so the duplicated nibble is the intended encoding
+        let cases: [(&str, &[u8]); 4] = [
+            ("add r1, r2", &[0b00100101, 0x12]),
+            ("add r1, 5", &[0b10100101, 0x11, 5]),
+            ("add r1, r1, 5", &[0b10100101, 0x11, 5]),
+            ("add r1, r2, 5", &[0b10100101, 0x12, 5]),
+        ];
+        for (source, bytes) in cases.iter() {
+            assert_eq!(assemble_string(source), *bytes, "{}", source);
+        }
+        assert_eq!(assemble_logs("add r1, r1, 5")[0].code(), Code::RedundantOperand);
+    }
+    
     #[test]
     fn lpc() {
         let buffer = assemble_string("lpc r15, r0");
@@ -181,6 +1158,22 @@ mod tests {
         assert_eq!(buffer[1], 0x0F);
     }
     
+    #[test]
+    fn special_register_alias() {
+        assert_eq!(assemble_string("lsp sp, r1, r2"), assemble_string("lsp r1, r2"));
+        assert_eq!(assemble_string("sadr adr, 0x1234"), assemble_string("sadr 0x1234"));
+        let buffer = assemble_string("lsp sp, r1, r2");
+        assert_eq!(buffer[0], 0b01001010);
+        assert_eq!(buffer[1], 0x21);
+        
+        assert!(assemble_logs("lsp pc, r1, r2").iter().any(|l| l.to_string().contains("LSP accesses sp, not pc")));
+        
+        // Only the first operand of a special register instruction is an alias, elsewhere they are names
+        assert_eq!(assemble_string("sp: nop\njmp sp"), assemble_string("sp: nop\njmp 0"));
+        assert_eq!(assemble_string("lr = 0x1234\nsadr lr"), assemble_string("sadr 0x1234"));
+        assert!(assemble_logs("add sp, r1")[0].message().contains("got: Ident(\"sp\")"));
+    }
+    
     #[test]
     fn jmp() {
         let buffer = assemble_string("jmp r0, r15");
@@ -220,6 +1213,778 @@ mod tests {
         assert_eq!(halt[2], 0);
     }
     
+    #[test]
+    fn relative_range() {
+        assert_eq!(assemble_string("rjmp 32767"), vec![0b11000110, 0xFF, 0x7F]);
+        assert_eq!(assemble_string("rjmp -32768"), vec![0b11000110, 0x00, 0x80]);
+        assert_eq!(assemble_string("rjmp 0xFFFE"), assemble_string("rjmp -2"));
+        
+        for source in ["rjmp 40000", "rcallnz 40000", "rjmp 30000 + 10000", "rjmpz -40000"] {
+            // Past -32768 the value doesn't fit in 16 bits either, which is a warning of its own
+            let logs: Vec<Log> = assemble_logs(source).into_iter().filter(Log::is_error).collect();
+            assert_eq!(logs.len(), 1, "{}", source);
+            assert_eq!(logs[0].code(), Code::AddressSpace);
+            assert!(logs[0].message().contains("out of range (-32768..32767)"), "{}", source);
+        }
+        assert!(assemble_logs("rjmp 40000")[0].message().ends_with("1:6: relative offset 40000 out of range (-32768..32767)"));
+        // Absolute jumps take the whole address space
+        assert!(assemble_logs("jmp 40000").is_empty());
+    }
+    
+    #[test]
+    fn relative_label() {
+        let labels = assemble_string("
+            rjmpz _skip
+            nop
+        _skip:
+            nop
+            rcallc _skip
+        ");
+        let basic = assemble_string("
+            rjmpz 5
+            nop
+            nop
+            rcallc 0xFFFE
+        ");
+        assert_eq!(basic, labels);
+        
+        // Absolute jumps are unaffected
+        let halt = assemble_string("nop\nhalt: jmp halt");
+        assert_eq!(halt[3], 2);
+        assert_eq!(halt[4], 0);
+    }
+    
+    #[test]
+    fn local_labels() {
+        let bytes = assemble_string("
+        first:
+            nop
+        .loop:
+            jmp .loop
+        second:
+            nop
+        .loop:
+            jmp .loop
+            .db .loop+2
+        ");
+        assert_eq!(&bytes[3..5], &[2, 0]);
+        assert_eq!(&bytes[8..10], &[7, 0]);
+        assert_eq!(&bytes[10..12], &[9, 0]);
+    }
+    
+    #[test]
+    fn numeric_labels() {
+        let bytes = assemble_string("
+        1:
+            nop
+            jmp 1f
+            jmp 1b
+        1:
+            jmp 1b
+            .db 1b+1
+        ");
+        assert_eq!(&bytes[3..5], &[8, 0]);
+        assert_eq!(&bytes[6..8], &[0, 0]);
+        assert_eq!(&bytes[9..11], &[8, 0]);
+        assert_eq!(&bytes[11..13], &[9, 0]);
+    }
+    
+    #[test]
+    fn global() {
+        let (lines, _) = parse_raw(".global start\nnop\nstart: jmp start\nhelper: ret", None);
+        let AssembleResult {link_info, logs, ..} = assemble_lines(&lines, &CodegenOptions::default());
+        assert!(logs.is_empty());
+        assert_eq!(link_info.globals, vec![(String::from("start"), 2)]);
+        
+        assert!(assemble_logs(".global missing").iter().any(|l| l.to_string().contains("global symbol missing is never defined")));
+    }
+    
+    #[test]
+    fn relocations() {
+        let (lines, _) = parse_raw("nop\ncall external\nrjmp external\n.db external", None);
+        let options = CodegenOptions {relocatable: true, allow: vec![Code::DbLabelWidth], ..Default::default()};
+        let AssembleResult {bytes: buffer, link_info, logs, ..} = assemble_lines(&lines, &options);
+        assert!(logs.is_empty());
+        assert_eq!(buffer.len(), 10);
+        let relocation = |offset, relative| Relocation {symbol: String::from("external"), offset, width: 2, relative};
+        assert_eq!(link_info.relocations, vec![relocation(3, false), relocation(6, true), relocation(8, false)]);
+        
+        // Without the flag the symbol is an error as usual
+        assert!(assemble_logs("call external").iter().any(|l| l.to_string().contains("unresolved symbol: external")));
+        
+        let (lines, _) = parse_raw("call external+2", None);
+        let logs = assemble_lines(&lines, &options).logs;
+        assert!(logs[0].to_string().contains("expression using external symbol external can not be relocated"));
+    }
+    
+    #[test]
+    fn unused_labels() {
+        let (lines, _) = parse_raw("
+        .global start
+        start:
+            jmp loop
+        loop:
+            jmp loop
+        1:
+        unused:
+            .db table+1
+        table:", None);
+        let options = CodegenOptions {warn_unused_labels: true, ..Default::default()};
+        let logs = assemble_lines(&lines, &options).logs;
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].to_string().contains(":8: label unused is never used"));
+        
+        // Off by default
+        assert!(assemble_lines(&lines, &CodegenOptions::default()).logs.is_empty());
+    }
+    
+    #[test]
+    fn duplicate_label() {
+        let logs = assemble_logs("start:\nnop\n\nstart:");
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].to_string().contains(":4: symbol start declared multiple times, previously defined at [unknown]:1"));
+    }
+    
+    #[test]
+    fn symbols() {
+        let (lines, _) = parse_raw("nop\nstart: set r1, 5\n.loop: dec r1\nrjmpnz .loop\n.line 0x20\ndata: .db 1", None);
+        let AssembleResult {bytes: code, symbols, logs, ..} = assemble_lines(&lines, &CodegenOptions::default());
+        assert!(logs.is_empty());
+        assert_eq!(symbols.keys().collect::<Vec<_>>(), ["data", "start", "start.loop"]);
+        assert_eq!(symbols["start"], 2);
+        assert_eq!(code[symbols["start"] as usize], 0b10101001);
+        assert_eq!(code[symbols["start.loop"] as usize], 0b00110111);
+        assert_eq!(code[symbols["data"] as usize..], [1]);
+        assert_eq!(code, assemble_lines(&lines, &CodegenOptions::default()).bytes);
+    }
+    
+    #[test]
+    fn endian() {
+        let (lines, _) = parse_raw("jmp 0xDEAD", None);
+        let big = CodegenOptions {endian: Endian::Big, ..Default::default()};
+        assert_eq!(assemble_lines(&lines, &CodegenOptions::default()).bytes[1..], [0xAD, 0xDE]);
+        assert_eq!(assemble_lines(&lines, &big).bytes[1..], [0xDE, 0xAD]);
+        
+        // Label patches, wide addresses and bytes of labels follow the same order, streamed or not
+        let source = "nop\nstart: ldr r1, 0x1234\njmp start\n.db start low(start) high(start)";
+        let (lines, _) = parse_raw(source, None);
+        let options = CodegenOptions {allow: vec![Code::DbLabelWidth], ..big};
+        let AssembleResult {bytes: code, logs, ..} = assemble_lines(&lines, &options);
+        assert!(logs.is_empty());
+        assert_eq!(code[4..6], [0x12, 0x34]);
+        assert_eq!(code[7..], [0x00, 0x02, 0x00, 0x02, 0x02, 0x00]);
+        let mut streamed = Vec::new();
+        assemble_to_writer(&lines, &options, &mut streamed).unwrap();
+        assert_eq!(streamed, code);
+    }
+    
+    #[test]
+    fn db_repeat() {
+        assert_eq!(assemble_string(".db 0xAA * 4"), vec![0xAA; 4]);
+        assert_eq!(assemble_string(".db 1 0 * 3 \"a\""), vec![1, 0, 0, 0, b'a']);
+        // Multiplying needs parentheses
+        assert_eq!(assemble_string(".db (2 * 3)"), vec![6]);
+        
+        let logs = assemble_logs(".db 0 * 0");
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].is_error());
+        assert!(logs[0].message().ends_with("repeat count must be at least 1"));
+        assert!(assemble_logs(".db 1 * 0x10001")[0].message().ends_with("repeat count 65537 exceeds the 16-bit address space"));
+        assert!(assemble_logs(".db 1 * r1")[0].message().contains("expected a repeat count"));
+    }
+    
+    #[test]
+    fn line_continuation() {
+        assert_eq!(assemble_string(".db 1 2 \\\n    3 4\nnop"), vec![1, 2, 3, 4, 0b00101001, 0x00]);
+        // Comments and blank lines can sit inside the table
+        assert_eq!(assemble_string(".db 1 2 \\ // first row\n\\\n    3 4 \\ // second row\n    5"), vec![1, 2, 3, 4, 5]);
+        assert_eq!(assemble_string(".db 1 \\"), vec![1]);
+        assert_eq!(assemble_string("add r1, \\\nr2"), assemble_string("add r1, r2"));
+        
+        // Errors are reported on the line the statement starts on
+        let logs = assemble_logs("nop\n.db 1 \\\n    frob");
+        assert!(logs[0].message().starts_with("[unknown]:2: "));
+    }
+    
+    #[test]
+    fn crlf() {
+        let source = "start: set r0, 5  \n.loop: add r0, r1\t\n.db \"a\" 2 // two\n\njmp start\n";
+        let windows = source.replace('\n', "\r\n");
+        let (lines, logs) = parse_raw(&windows, None);
+        assert!(logs.is_empty());
+        assert_eq!(assemble_lines(&lines, &CodegenOptions::default()).bytes, assemble_string(source));
+        
+        // A doubled `\r` is left over by `lines`
+        let (_, logs) = parse_raw(&source.replace('\n', "\r\r\n"), None);
+        assert!(logs.is_empty());
+    }
+    
+    #[test]
+    fn label_before_directive() {
+        let (lines, logs) = parse_raw("nop\nbuf: .db 1 2 3 4\nhere: .line 0x100\nafter: nop", None);
+        assert!(logs.is_empty());
+        assert!(matches!(lines[1].data, LineData::Label(ref l) if l == "buf"));
+        assert!(matches!(lines[2].data, LineData::Directive(Directive::DB(ref data)) if data.len() == 4));
+        
+        let AssembleResult {bytes: code, symbols, logs, ..} = assemble_lines(&lines, &CodegenOptions::default());
+        assert!(logs.is_empty());
+        assert_eq!(symbols["buf"], 2);
+        assert_eq!(code[2..6], [1, 2, 3, 4]);
+        // The label is where the padding starts, not where it ends
+        assert_eq!(symbols["here"], 6);
+        assert_eq!(symbols["after"], 0x100);
+    }
+    
+    #[test]
+    fn include_origin() {
+        let dir = std::env::temp_dir().join(format!("x69-codegen-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.asm"), "start: nop\n.include \"lib.asm\"").unwrap();
+        std::fs::write(dir.join("lib.asm"), "nop\nstart: ret").unwrap();
+        
+        let (lines, parse_logs) = parse_file(&ParseOptions {origin: dir.join("main.asm"), ..Default::default()});
+        assert!(parse_logs.is_empty());
+        let logs = assemble_lines(&lines, &CodegenOptions::default()).logs;
+        let message = logs[0].to_string();
+        assert!(message.contains("lib.asm:2: symbol start declared multiple times"));
+        assert!(message.contains("main.asm:1"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    
+    #[test]
+    fn missing_include() {
+        let dir = std::env::temp_dir().join(format!("x69-missing-include-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.asm"), "nop\n    .include \"missing.asm\"\nnop").unwrap();
+        
+        let options = ParseOptions {origin: dir.join("main.asm"), ..Default::default()};
+        let (lines, logs) = parse_file(&options);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].code(), Code::Io);
+        assert!(logs[0].message().contains("main.asm:2:14: could not include \"missing.asm\": "));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    
+    #[test]
+    fn include_once() {
+        let dir = std::env::temp_dir().join(format!("x69-include-once-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.asm"), ".include \"a.asm\"\n.include \"header.asm\"\n.include \"./header.asm\"").unwrap();
+        std::fs::write(dir.join("a.asm"), ".include \"header.asm\"\n.db 1").unwrap();
+        std::fs::write(dir.join("header.asm"), ".db 0x42").unwrap();
+        
+        let options = ParseOptions {origin: dir.join("main.asm"), ..Default::default()};
+        let (lines, parse_logs) = parse_file(&options);
+        assert!(parse_logs.is_empty());
+        let AssembleResult {bytes: assembly, logs, ..} = assemble_lines(&lines, &CodegenOptions::default());
+        assert!(logs.is_empty());
+        assert_eq!(assembly, vec![0x42, 1]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    
+    #[test]
+    fn include_cycle() {
+        let dir = std::env::temp_dir().join(format!("x69-include-cycle-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.asm"), ".include \"a.asm\"\n.db 2").unwrap();
+        std::fs::write(dir.join("a.asm"), ".db 1\n.include \"main.asm\"").unwrap();
+        let file = |name: &str| dir.join(name).canonicalize().unwrap().display().to_string();
+        
+        let options = || ParseOptions {origin: dir.join("main.asm"), ..Default::default()};
+        let (lines, logs) = parse_file(&options());
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].is_error());
+        assert!(logs[0].message().contains(&format!("a.asm:2:10: include cycle: {} -> {} -> {}", file("main.asm"), file("a.asm"), file("main.asm"))));
+        assert_eq!(assemble_lines(&lines, &CodegenOptions::default()).bytes, vec![1, 2]);
+        
+        // Including itself is the shortest cycle
+        std::fs::write(dir.join("main.asm"), ".include \"main.asm\"").unwrap();
+        let (_, logs) = parse_file(&options());
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].message().contains(&format!("include cycle: {} -> {}", file("main.asm"), file("main.asm"))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    
+    #[test]
+    fn end_directive() {
+        assert_eq!(assemble_string("nop\n.end\nset r0, 5\nfrob"), vec![0b00101001, 0x00]);
+        assert_eq!(assemble_string("nop; .end; ret"), vec![0b00101001, 0x00]);
+        assert_eq!(assemble_string(".if 1\nnop\n.end\n.endif"), vec![0b00101001, 0x00]);
+        assert!(assemble_logs(".end nop")[0].message().contains("1:6: unexpected token after .end: nop"));
+        
+        // Only the included file stops early
+        let dir = std::env::temp_dir().join(format!("x69-end-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.asm"), ".include \"lib.asm\"\n.db 2").unwrap();
+        std::fs::write(dir.join("lib.asm"), ".db 1\n.end\n.db 0xFF").unwrap();
+        
+        let options = ParseOptions {origin: dir.join("main.asm"), ..Default::default()};
+        let (lines, parse_logs) = parse_file(&options);
+        assert!(parse_logs.is_empty());
+        let AssembleResult {bytes: assembly, logs, ..} = assemble_lines(&lines, &CodegenOptions::default());
+        assert!(logs.is_empty());
+        assert_eq!(assembly, vec![1, 2]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    
+    #[test]
+    fn invalid_utf8() {
+        let dir = std::env::temp_dir().join(format!("x69-utf8-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.asm"), b"nop\n.db \"\xFF\xFE\"").unwrap();
+        
+        let (lines, logs) = parse_file(&ParseOptions {origin: dir.join("main.asm"), ..Default::default()});
+        assert!(lines.is_empty());
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].code(), Code::Io);
+        assert!(logs[0].message().ends_with("main.asm: input is not valid UTF-8 at byte 9"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    
+    #[test]
+    fn line_past_address_space() {
+        let logs = assemble_logs(".line 0x10000\n.line 0xFFFF + 2\n.line -1");
+        assert_eq!(logs.len(), 3);
+        assert!(logs[0].message().contains("1:7: line offset 0x10000 is outside the 16-bit address space"));
+        assert!(logs[1].message().contains("line offset 0x10001 is outside the 16-bit address space"));
+        assert!(logs[2].message().contains("line offset -1 is outside the 16-bit address space"));
+        
+        // Jumping back after overflowing still reports the overflow
+        let logs = assemble_logs(".repeat 0x8001\nnop\n.endr\n.line 0x10\nnop");
+        assert!(logs.iter().any(|l| l.is_error() && l.message().contains("program size 0x10002 exceeds 16-bit address space")));
+        assert_eq!(assemble_string(".line 0xFFFE\nnop").len(), 0x10000);
+    }
+    
+    #[test]
+    fn streaming() {
+        let sources = [
+            "start: nop\njmp end\n.line 0x11\nset r1, 5\nldr r2, data\nldr r3, 0x1234\nadd r1, r2, 7\nend: jmp start\ndata: .db 9",
+            "data: .db 1 2 data \"hi\"\nli r2, 0x1234\njmp data + 1",
+            ".repeat 300\nnop\n.endr\nlast: .db last",
+        ];
+        let options = CodegenOptions {allow: vec![Code::DbLabelWidth], ..Default::default()};
+        for source in sources.iter() {
+            let (lines, parse_logs) = parse_raw(source, None);
+            assert!(parse_logs.is_empty());
+            let AssembleResult {bytes: buffered, logs, ..} = assemble_lines(&lines, &options);
+            assert!(logs.is_empty());
+            
+            let mut streamed = std::io::Cursor::new(Vec::new());
+            let logs = assemble_to_writer(&lines, &options, &mut streamed).unwrap();
+            assert!(logs.is_empty(), "{:?}", logs);
+            assert_eq!(streamed.into_inner(), buffered, "{}", source);
+        }
+        
+        let (lines, _) = parse_raw("nop\n.line 0\njmp missing", None);
+        let logs = assemble_to_writer(&lines, &CodegenOptions::default(), &mut Vec::new()).unwrap();
+        assert!(logs[0].message().contains("line offset 0x0 moves backwards from 0x2"));
+        assert!(logs[1].message().contains("unresolved symbol: missing"));
+    }
+    
+    #[test]
+    fn register_ranges() {
+        // Pop runs backwards so it restores what the same push saved
+        assert_eq!(assemble_string("push r1-r3"), assemble_string("push r1\npush r2\npush r3"));
+        assert_eq!(assemble_string("pop r1-r3"), assemble_string("pop r3\npop r2\npop r1"));
+        
+        // Each register is its own instruction in the line map, the sizes come from them too
+        let (lines, _) = parse_raw("push r0-r2\nend: jmp end", None);
+        let AssembleResult {bytes, line_map, ..} = assemble_lines(&lines, &CodegenOptions::default());
+        assert_eq!(bytes[6..], [0b11000100, 0x06, 0x00]);
+        let ranges: Vec<_> = line_map.iter().map(|m| (m.range.clone(), m.line)).collect();
+        assert_eq!(ranges, [(0..2, 0), (2..4, 0), (4..6, 0), (6..9, 1)]);
+    }
+    
+    #[test]
+    fn align_warning() {
+        let options = CodegenOptions {warn_align: true, ..Default::default()};
+        let align = |source: &str| {
+            let (lines, _) = parse_raw(source, None);
+            assemble_lines(&lines, &options).logs
+        };
+        let logs = align(".db 1 2 3\ncode: nop");
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].code(), Code::Alignment);
+        assert!(logs[0].message().ends_with(":2: instruction at 0x3 follows 3 bytes of .db, an odd count leaves it misaligned"));
+        
+        // Every `.db` before the instruction counts together
+        assert!(align(".db 1\n.db 2\nnop").is_empty());
+        assert!(align(".db \"hi\"\nnop").is_empty());
+        // `.line` places code wherever it was asked to
+        assert!(align(".db 1 2 3\n.line 0x11\nnop").is_empty());
+        // Off unless asked for
+        assert!(assemble_logs(".db 1 2 3\nnop").is_empty());
+    }
+    
+    #[test]
+    fn label_suggestion() {
+        let source = "loop: nop\njmp looop\njmp elsewhere";
+        let logs = assemble_logs(source);
+        assert_eq!(logs.len(), 2);
+        assert!(logs[0].message().ends_with("unresolved symbol: looop, did you mean `loop`? [PENDING LINKER]"));
+        assert!(logs[1].message().ends_with("unresolved symbol: elsewhere [PENDING LINKER]"));
+        
+        // Streaming reports them the same way
+        let (lines, _) = parse_raw(source, None);
+        let streamed = assemble_to_writer(&lines, &CodegenOptions::default(), &mut Vec::new()).unwrap();
+        assert_eq!(streamed.iter().map(Log::message).collect::<Vec<_>>(), logs.iter().map(Log::message).collect::<Vec<_>>());
+    }
+    
+    #[test]
+    fn streaming_diagnostics() {
+        let source = "
+        .global start
+        .global missing
+        .entry nowhere
+        .entry start
+        start: nop
+        unused: .db 1 2 3
+        after_db: nop
+        .line 0x13
+        dup: nop
+        dup: jmp after_db
+        .db low(1@) 1
+        1@: add r1, r2
+        .line 0xFFFF
+        add r1, r2
+        _end: .db _end";
+        let (lines, _) = parse_raw(source, None);
+        let options = CodegenOptions {warn_align: true, warn_unused_labels: true, ..Default::default()};
+        let AssembleResult {logs, ..} = assemble_lines(&lines, &options);
+        let messages: Vec<String> = logs.iter().map(Log::message).collect();
+        for expected in [
+            "global symbol missing is never defined",
+            "entry point nowhere is never defined",
+            "entry point declared multiple times, previously set at",
+            "label unused is never used",
+            "instruction at 0x5 follows 3 bytes of .db, an odd count leaves it misaligned",
+            "line offset will not guarantee instruction alignment",
+            "symbol dup declared multiple times, previously defined at",
+            "program size 0x10001 exceeds 16-bit address space",
+            "label _end at 0x10001 exceeds 16-bit address space",
+        ] {
+            assert!(messages.iter().any(|msg| msg.contains(expected)), "{} not in {:?}", expected, messages);
+        }
+        
+        let streamed = assemble_to_writer(&lines, &options, &mut Vec::new()).unwrap();
+        let mut streamed: Vec<String> = streamed.iter().map(Log::message).collect();
+        let mut messages = messages;
+        streamed.sort();
+        messages.sort();
+        assert_eq!(streamed, messages);
+    }
+    
+    #[test]
+    fn db_label_width() {
+        let (lines, _) = parse_raw(".db a b\n.db a\na: nop\nb: nop", None);
+        let AssembleResult {logs, ..} = assemble_lines(&lines, &CodegenOptions::default());
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].code(), Code::DbLabelWidth);
+        assert!(logs[0].message().ends_with(":1: label in .db occupies 2 bytes (full address); use low()/high() for a single byte"));
+        assert_eq!(assemble_to_writer(&lines, &CodegenOptions::default(), &mut Vec::new()).unwrap().len(), 1);
+        
+        assert!(assemble_logs(".db low(a) high(a)\na: nop").is_empty());
+    }
+    
+    #[test]
+    fn cpu() {
+        let (lines, _) = parse_raw("start: callz start\ncall start\nrcallnc start", None);
+        let AssembleResult {bytes: buffered, logs, ..} = assemble_lines(&lines, &CodegenOptions::default());
+        assert!(logs.is_empty());
+        
+        let options = CodegenOptions {cpu: Cpu::X69V0, ..Default::default()};
+        let AssembleResult {bytes: code, logs, ..} = assemble_lines(&lines, &options);
+        assert_eq!(logs.len(), 2);
+        assert!(logs[0].message().ends_with(":1: CALLZ not available on cpu x69-v0"));
+        assert!(logs[1].message().ends_with(":3: RCALLNC not available on cpu x69-v0"));
+        assert_eq!(code, buffered);
+        
+        let logs = assemble_to_writer(&lines, &options, &mut Vec::new()).unwrap();
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0].code(), Code::UnknownInstruction);
+    }
+    
+    #[test]
+    fn parallel_includes() {
+        let dir = std::env::temp_dir().join(format!("x69-parallel-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut main = String::from("start: nop\n");
+        for i in 0..16 {
+            main += &format!(".include \"part{}.asm\"\n", i);
+            std::fs::write(dir.join(format!("part{}.asm", i)), format!(".include \"header.asm\"\npart{}: .db {} start\nfrob", i, i)).unwrap();
+        }
+        main += ".include \"header.asm\"\n.include \"missing.asm\"\njmp part3";
+        std::fs::write(dir.join("main.asm"), main).unwrap();
+        std::fs::write(dir.join("header.asm"), "header: .db 0xAA\n.include \"main.asm\"").unwrap();
+        
+        let parse = |parallel_includes| {
+            let options = ParseOptions {origin: dir.join("main.asm"), parallel_includes, ..Default::default()};
+            let (lines, logs) = parse_file(&options);
+            let AssembleResult {bytes: assembly, logs: asm_logs, ..} = assemble_lines(&lines, &CodegenOptions::default());
+            let messages: Vec<String> = logs.iter().chain(asm_logs.iter()).map(Log::message).collect();
+            (assembly, messages)
+        };
+        // The cycle back to main.asm is reported once, by the first include of the header
+        let serial = parse(false);
+        assert_eq!(serial.1.len(), 19);
+        assert_eq!(serial.1.iter().filter(|message| message.contains("include cycle")).count(), 1);
+        for _ in 0..4 {
+            assert_eq!(parse(true), serial);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    
+    #[test]
+    fn parse_cache() {
+        use crate::parser::ParseCache;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        
+        let dir = std::env::temp_dir().join(format!("x69-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.asm"), ".db 1\n.include \"lib.asm\"").unwrap();
+        std::fs::write(dir.join("lib.asm"), ".db 2").unwrap();
+        
+        let cache = Rc::new(RefCell::new(ParseCache::default()));
+        let assemble_with = |lenient_operands| {
+            let options = ParseOptions {origin: dir.join("main.asm"), cache: cache.clone(), lenient_operands, ..Default::default()};
+            assemble_lines(&parse_file(&options).0, &CodegenOptions::default()).bytes
+        };
+        let assemble = || assemble_with(false);
+        assert_eq!(assemble(), vec![1, 2]);
+        assert_eq!(cache.borrow().hits, 0);
+        assert_eq!(assemble(), vec![1, 2]);
+        assert_eq!(cache.borrow().hits, 1);
+        
+        // A changed include invalidates everything that includes it
+        std::fs::write(dir.join("lib.asm"), ".db 3").unwrap();
+        assert_eq!(assemble(), vec![1, 3]);
+        assert_eq!(cache.borrow().hits, 1);
+        std::fs::write(dir.join("main.asm"), ".db 4\n.include \"lib.asm\"").unwrap();
+        assert_eq!(assemble(), vec![4, 3]);
+        assert_eq!(cache.borrow().hits, 2);
+        
+        // So does changing an option, since it can change the parsed lines
+        assert_eq!(assemble_with(true), vec![4, 3]);
+        assert_eq!(cache.borrow().hits, 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    
+    #[test]
+    fn label_bytes() {
+        let mut source = String::from(".line 0x1234\ntarget: .db low(target) high(target) HIGH(target) low(.local)\n.local: nop");
+        let binary = assemble_string(&source);
+        assert_eq!(binary[0x1234..], [0x34, 0x12, 0x12, 0x38, 0b00101001, 0]);
+        
+        source += "\n.db low(nowhere)";
+        assert!(assemble_logs(&source)[0].message().contains("unresolved symbol: nowhere"));
+        assert!(assemble_logs(".db low(1)")[0].message().contains("expected a label, got: Immediate(\"1\")"));
+        assert!(assemble_logs(".db high(x")[0].message().contains("expected ')' after label"));
+        assert!(assemble_logs(".db")[0].message().contains("empty db field"));
+    }
+    
+    #[test]
+    fn checksum() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16(&[]), 0xFFFF);
+        assert_eq!(crc16(&assemble_string("nop")), 0xA171);
+    }
+    
+    #[test]
+    fn dump() {
+        let mut bytes = b"Hello, x69!\n".to_vec();
+        bytes.extend(0..8);
+        assert_eq!(hexdump(&bytes), concat!(
+            "00000000: 4865 6c6c 6f2c 2078 3639 210a 0001 0203  Hello, x69!.....\n",
+            "00000010: 0405 0607                                ....\n",
+        ));
+        assert_eq!(hexdump(&[]), "");
+    }
+    
+    #[test]
+    fn disassembly() {
+        assert_eq!(disassemble(&assemble_string("add r1, r2")), vec!["ADD r1, r2"]);
+        
+        let source = "
+            nop
+            clr r3
+            inc r4
+            not r1, r2
+            add r1, 0x05
+            sub r1, r2, 0x10
+            set r7, 0xFF
+            cmp r2, r9
+            ldr r0, 0x0F
+            str r5, 0x1234
+            lsp r1, r2
+            jmp 0xBEEF
+            jmp r3, r4
+            ret";
+        let bytes = assemble_string(source);
+        let lines = disassemble(&bytes);
+        assert_eq!(lines.len(), 14);
+        assert_eq!(lines[9], "STR r5, 0x1234");
+        assert_eq!(lines[11], "JMP 0xBEEF");
+        assert_eq!(assemble_string(&lines.join("\n")), bytes);
+        
+        // The wide form keeps its register and can't be mistaken for the short one
+        let bytes = assemble_string("ldr r3, 0x12\nldr r3, 0x1234\nldr r0, 0x1234");
+        assert_eq!(disassemble(&bytes), vec!["LDR r3, 0x12", "LDR r3, 0x1234", "LDR r0, 0x1234"]);
+        
+        // Leftover and unknown bytes are kept as data
+        assert_eq!(disassemble(&[0b00101001, 0x00, 0xFF, 0b10101001]), vec!["NOP", ".db 0xFF", ".db 0xA9"]);
+    }
+    
+    #[test]
+    fn assemble_result() {
+        let (lines, _) = parse_raw(".global main\nmain: set r0, 1\n.loop: jmp .loop\n.db main", None);
+        let result = assemble_lines(&lines, &CodegenOptions::default());
+        assert_eq!(result.bytes, [0b10101001, 0x00, 1, 0b11000100, 0x03, 0x00, 0x00, 0x00]);
+        assert_eq!(result.symbols.into_iter().collect::<Vec<_>>(), [("main".to_owned(), 0), ("main.loop".to_owned(), 3)]);
+        assert_eq!(result.link_info.globals, [("main".to_owned(), 0)]);
+        assert_eq!(result.line_map.iter().map(|m| m.range.clone()).collect::<Vec<_>>(), [0..3, 3..6, 6..8]);
+        assert_eq!(result.logs.len(), 1);
+        assert_eq!(result.logs[0].code(), Code::DbLabelWidth);
+    }
+    
+    #[test]
+    fn line_map() {
+        let (lines, _) = parse_raw("start: nop\n\n.line 0x10\nldr r1, 0x1234; .db 1 2\njmp start", None);
+        let AssembleResult {bytes: code, line_map, logs, ..} = assemble_lines(&lines, &CodegenOptions::default());
+        assert!(logs.is_empty());
+        let ranges: Vec<_> = line_map.iter().map(|m| (m.range.clone(), m.line)).collect();
+        assert_eq!(ranges, [(0..2, 0), (0x10..0x14, 3), (0x14..0x16, 3), (0x16..0x19, 4)]);
+        
+        let line_at = |address| line_map.iter().find(|m| m.range.contains(&address)).map(|m| m.line);
+        assert_eq!(line_at(0x16), Some(4));
+        assert_eq!(code[0x16], 0b11000100);
+        assert_eq!(line_at(0x8), None);
+        
+        let mut table = Vec::new();
+        write_line_map(&mut table, &line_map).unwrap();
+        assert_eq!(table[..4], LINE_MAP_MAGIC);
+        assert_eq!(table[5..11], [1, 0, 4, 0, 0, 0]);
+        assert_eq!(table[11..22], *b"\x09\x00[unknown]");
+        assert_eq!(table[table.len() - 10..], [0x16, 0, 3, 0, 0, 0, 5, 0, 0, 0]);
+    }
+    
+    #[test]
+    fn absolute_labels() {
+        let (lines, _) = parse_raw("vga = 0x8000\nstart: jmp vga\nnext: ldr r1, vga + 2", None);
+        let AssembleResult {bytes, symbols, absolute, logs, ..} = assemble_lines(&lines, &CodegenOptions::default());
+        assert!(logs.is_empty());
+        // `vga` takes no space, `start` is still at 0
+        assert_eq!(bytes, [0b11000100, 0x00, 0x80, 0b10010010, 0x11, 0x02, 0x80]);
+        let mut map = Vec::new();
+        write_map(&mut map, &symbols, &absolute, None).unwrap();
+        assert_eq!(String::from_utf8(map).unwrap(), "0x0003 next\n0x0000 start\n0x8000 vga absolute\n");
+        
+        let (lines, _) = parse_raw("vga = 0x8000\nvga: nop", None);
+        let AssembleResult {logs, ..} = assemble_lines(&lines, &CodegenOptions::default());
+        assert_eq!(logs[0].code(), Code::DuplicateSymbol);
+    }
+    
+    #[test]
+    fn deterministic_map() {
+        let source: String = (0..200).map(|i| format!("label{}: nop\n", (i * 37) % 200)).collect::<String>() + "io = 0xFF00\n.entry label7\njmp labl";
+        let run = || {
+            let (lines, _) = parse_raw(&source, None);
+            let AssembleResult {symbols, absolute, entry, logs, ..} = assemble_lines(&lines, &CodegenOptions {warn_unused_labels: true, ..Default::default()});
+            let mut map = Vec::new();
+            write_map(&mut map, &symbols, &absolute, entry.as_ref()).unwrap();
+            (map, logs.iter().map(Log::message).collect::<Vec<_>>())
+        };
+        let (first_map, first_logs) = run();
+        let (second_map, second_logs) = run();
+        assert_eq!(first_map, second_map);
+        assert_eq!(first_logs, second_logs);
+        assert!(String::from_utf8(first_map).unwrap().starts_with("entry 0x0016 label7\n0xFF00 io absolute\n0x0000 label0\n0x015A label1\n0x0104 label10\n"));
+    }
+    
+    #[test]
+    fn assemble_into_buffer() {
+        use crate::codegen::assemble_into;
+        
+        let (lines, _) = parse_raw("start: set r1, 7\n.line 0x10\nloop: jmp loop\nrjmp start", None);
+        let mut rom = [0xFF; 256];
+        assert_eq!(assemble_into(&lines, &CodegenOptions::default(), &mut rom, 0x8000).unwrap(), 0x16);
+        assert_eq!(rom[..3], [0b10101001, 0x11, 7]);
+        assert!(rom[3..0x10].iter().all(|b| *b == 0));
+        // Absolute addresses include the base, relative ones don't change
+        assert_eq!(rom[0x10..0x16], [0b11000100, 0x10, 0x80, 0b11000110, 0xED, 0xFF]);
+        assert!(rom[0x16..].iter().all(|b| *b == 0xFF));
+        
+        // The options apply like they do everywhere else
+        let options = CodegenOptions {endian: Endian::Big, fill: 0xAA, ..Default::default()};
+        assert_eq!(assemble_into(&lines, &options, &mut rom, 0x8000).unwrap(), 0x16);
+        assert!(rom[3..0x10].iter().all(|b| *b == 0xAA));
+        assert_eq!(rom[0x10..0x16], [0b11000100, 0x80, 0x10, 0b11000110, 0xFF, 0xED]);
+        
+        let (lines, _) = parse_raw("nop\n.line 0xFE\nnop\nnop", None);
+        let mut rom = [0xFF; 256];
+        let logs = assemble_into(&lines, &CodegenOptions::default(), &mut rom, 0).unwrap_err();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].message(), "[unknown]:4: program is 258 bytes, the buffer only holds 256");
+        assert!(rom.iter().all(|b| *b == 0xFF));
+        
+        // Labels past the top of memory are caught like any other overflow
+        let (lines, _) = parse_raw(".line 0xFE\nend: jmp end", None);
+        assert!(assemble_into(&lines, &CodegenOptions::default(), &mut [0; 0x200], 0xFF80).is_err());
+    }
+    
+    #[test]
+    fn entry_point() {
+        let (lines, _) = parse_raw("init: nop\n.entry main\nmain: jmp init", None);
+        let AssembleResult {symbols, absolute, entry, logs, ..} = assemble_lines(&lines, &CodegenOptions {warn_unused_labels: true, ..Default::default()});
+        assert!(logs.is_empty());
+        assert_eq!(entry, Some(("main".to_owned(), 2)));
+        let mut map = Vec::new();
+        write_map(&mut map, &symbols, &absolute, entry.as_ref()).unwrap();
+        assert_eq!(String::from_utf8(map).unwrap(), "entry 0x0002 main\n0x0000 init\n0x0002 main\n");
+        
+        let (lines, _) = parse_raw(".entry start\n.entry main\nmain: nop", None);
+        let AssembleResult {entry, logs, ..} = assemble_lines(&lines, &CodegenOptions::default());
+        assert_eq!(entry, None);
+        let messages: Vec<_> = logs.iter().map(|log| (log.code(), log.message())).collect();
+        assert_eq!(messages, [
+            (Code::DuplicateSymbol, "[unknown]:2: entry point declared multiple times, previously set at [unknown]:1".to_owned()),
+            (Code::UnresolvedSymbol, "[unknown]:1: entry point start is never defined".to_owned()),
+        ]);
+    }
+    
+    #[test]
+    fn object() {
+        let (lines, _) = parse_raw(".global start\nstart: call external", None);
+        let AssembleResult {bytes: code, link_info, ..} = assemble_lines(&lines, &CodegenOptions {relocatable: true, ..Default::default()});
+        let mut object = Vec::new();
+        write_object(&mut object, &code, &link_info).unwrap();
+        
+        assert_eq!(object[..4], OBJECT_MAGIC);
+        assert_eq!(object[4], OBJECT_VERSION);
+        assert_eq!(u16::from_le_bytes([object[5], object[6]]), 1);
+        assert_eq!(u16::from_le_bytes([object[7], object[8]]), 1);
+        assert_eq!(u32::from_le_bytes([object[9], object[10], object[11], object[12]]), 3);
+        
+        let symbol = &object[13..];
+        assert_eq!(symbol[..2], [5, 0]);
+        assert_eq!(&symbol[2..7], b"start");
+        assert_eq!(symbol[7..9], [0, 0]);
+        
+        let relocation = &symbol[9..];
+        assert_eq!(relocation[..2], [8, 0]);
+        assert_eq!(&relocation[2..10], b"external");
+        assert_eq!(relocation[10..14], [1, 0, 2, 0]);
+        
+        assert_eq!(relocation[14..], code[..]);
+    }
+    
+    #[test]
+    fn directive_case() {
+        assert_eq!(assemble_string(".DB 1 2"), assemble_string(".db 1 2"));
+        assert_eq!(assemble_string(".Line 2\n.REPEAT 2; nop; .EndR"), assemble_string(".line 2\nnop\nnop"));
+    }
+    
     #[test]
     fn db() {
         let bytes = assemble_string("array: .db 0 1 array \"hello\" 3 4");
@@ -239,6 +2004,55 @@ mod tests {
         assert_eq!(buffer[0x1236], 0x12);
     } 
     
+    #[test]
+    fn overlapping_regions() {
+        // Going back to fill padding is fine
+        let source = ".line 4\nclr r1\n.line 0\nclr r0\nnop";
+        assert!(assemble_logs(source).is_empty());
+        assert_eq!(assemble_string(source), assemble_string("clr r0\nnop\nclr r1"));
+        
+        let logs = assemble_logs(".line 4\nnop\nnop\n.line 0\nnop\n.line 6\nclr r2");
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].to_string().contains(":6: region 0x6..0x8 overlaps bytes already written at 0x4..0x8"));
+    }
+    
+    #[test]
+    fn empty_padding() {
+        for (source, warns) in [("nop\n.line 2\nnop", true), ("nop\n.line 4\nnop", false), (".line 4\n.line 4\nnop", true), (".line 0\nnop", true), ("nop\n.line 0x10\n.line 0x10", true)] {
+            let logs = assemble_logs(source);
+            assert_eq!(logs.iter().any(|log| log.code() == Code::EmptyPadding), warns, "{}", source);
+            if warns {
+                assert_eq!(logs.len(), 1, "{}", source);
+                assert!(logs[0].message().ends_with("line directive produced no padding"));
+            }
+            
+            let (lines, _) = parse_raw(source, None);
+            let streamed = assemble_to_writer(&lines, &CodegenOptions::default(), &mut Vec::new()).unwrap();
+            assert_eq!(streamed.len(), logs.len(), "{}", source);
+        }
+    }
+    
+    #[test]
+    fn fill() {
+        let (lines, _) = parse_raw("nop\n.line 5\n.db 1", None);
+        let AssembleResult {bytes: buffer, ..} = assemble_lines(&lines, &CodegenOptions {fill: 0xFF, ..Default::default()});
+        assert_eq!(buffer, vec![0b00101001, 0x00, 0xFF, 0xFF, 0xFF, 1]);
+    }
+    
+    #[test]
+    fn address_space_overflow() {
+        let logs = assemble_logs("
+            .line 0xFFFE
+            add r1, r2
+            add r1, r2
+        _end:
+            .db _end");
+        let errors: Vec<String> = logs.iter().filter(|l| l.is_error()).map(|l| l.to_string()).collect();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].contains("program size 0x10002 exceeds 16-bit address space"));
+        assert!(errors[1].contains("label _end at 0x10002 exceeds 16-bit address space"));
+    }
+    
     #[test]
     fn ldr_sdr() {
         let buffer = assemble_string("ldr r0, 15");
@@ -250,5 +2064,73 @@ mod tests {
         assert_eq!(buffer[0], 0b10010001);
         assert_eq!(buffer[1], 0);
         assert_eq!(buffer[2], 150);
+        
+        // Addresses past a byte use the wide form
+        assert_eq!(assemble_string("ldr r3, 0x1234"), vec![0b10010010, 0x33, 0x34, 0x12]);
+        assert_eq!(assemble_string("str r3, 0x100"), vec![0b10010011, 0x33, 0x00, 0x01]);
+        assert_eq!(assemble_string("nop\nldr r1, data\ndata: .db 7"), vec![0b00101001, 0, 0b10010010, 0x11, 6, 0, 7]);
+    }
+    
+    #[test]
+    fn wide_immediate() {
+        assert_eq!(assemble_string("addw r1, r2, 0x1234"), vec![0b10111010, 0x12, 0x34, 0x12]);
+        assert_eq!(assemble_string("addw r3, r3, 0x100 + 4"), vec![0b10111010, 0x33, 0x04, 0x01]);
+        let big = CodegenOptions {endian: Endian::Big, ..Default::default()};
+        let (lines, _) = parse_raw("addw r1, r2, 0x1234", None);
+        assert_eq!(assemble_lines(&lines, &big).bytes, [0b10111010, 0x12, 0x12, 0x34]);
+        
+        // Disassembles back to the same bytes, and doesn't swallow the next instruction
+        let bytes = assemble_string("addw r1, r2, 0xBEEF\nadd r1, 5\nnop");
+        let lines = disassemble(&bytes);
+        assert_eq!(lines, vec!["ADDW r1, r2, 0xBEEF", "ADD r1, 0x05", "NOP"]);
+        assert_eq!(assemble_string(&lines.join("\n")), bytes);
+        
+        assert!(assemble_logs("addw r1, 0x1234")[0].message().contains("expected a register"));
+        let options = CodegenOptions {cpu: Cpu::X69V0, ..Default::default()};
+        let (lines, _) = parse_raw("addw r1, r2, 1", None);
+        assert!(assemble_lines(&lines, &options).logs[0].message().ends_with("ADDW not available on cpu x69-v0"));
+    }
+    
+    #[test]
+    fn malformed_lines() {
+        use crate::codegen::{Register, accepts};
+        use crate::expression::Expression;
+        use crate::instruction::Instruction;
+        use crate::parser::{DataByte, Directive, Line, LineData, Parameters};
+        use std::sync::Arc;
+        
+        let r = |n| Register::from_u8(n).unwrap();
+        let params = [
+            Parameters::None,
+            Parameters::Label("nowhere".to_owned()),
+            Parameters::Expression(Expression::Symbol("here".to_owned())),
+            Parameters::OneRegister(r(15)),
+            Parameters::LongImmediate(0xFFFF),
+            Parameters::TwoRegisters(r(0), r(15)),
+            Parameters::OneRegisterImmediate(r(15), 0xFF),
+            Parameters::OneRegisterLongImmediate(r(15), 0xFFFF),
+            Parameters::OneRegisterExpression(r(1), Expression::Symbol("here".to_owned())),
+            Parameters::TwoRegistersImmediate(r(15), r(15), 0xFF),
+            Parameters::TwoRegistersLongImmediate(r(15), r(15), 0xFFFF),
+        ];
+        let origin = Arc::new(String::from("fuzz.asm"));
+        let line = |line, data| Line {origin: origin.clone(), line, data, comment: None};
+        
+        // Every instruction with every shape of operands, surrounded by odd directives
+        for name in Instruction::iter() {
+            for params in params.iter() {
+                let lines = vec![
+                    line(0, LineData::Label("here".to_owned())),
+                    line(1, LineData::Directive(Directive::DB(vec![DataByte::Label("missing".to_owned()), DataByte::Byte(0)]))),
+                    line(2, LineData::Directive(Directive::Line(0))),
+                    line(3, LineData::Instruction {name: *name, params: params.clone()}),
+                    line(4, LineData::Directive(Directive::Line(0xFFFF))),
+                    line(5, LineData::Instruction {name: *name, params: params.clone()}),
+                ];
+                let AssembleResult {logs, ..} = assemble_lines(&lines, &CodegenOptions::default());
+                let mismatch = logs.iter().any(|l| l.message().contains("can not take operands"));
+                assert_eq!(mismatch, !accepts(name.operand_mode(), params), "{} {:?}", name.to_str(), params);
+            }
+        }
     }
 }
@@ -0,0 +1,286 @@
+use crate::lexer::Token;
+use crate::prelude::*;
+
+use logos::Lexer;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    ShiftLeft,
+    ShiftRight,
+    And,
+    Or,
+}
+
+impl BinaryOp {
+    fn from_token(token: Token) -> Option<Self> {
+        match token {
+            Token::Plus       => Some(Self::Add),
+            Token::Minus      => Some(Self::Sub),
+            Token::Star       => Some(Self::Mul),
+            Token::ShiftLeft  => Some(Self::ShiftLeft),
+            Token::ShiftRight => Some(Self::ShiftRight),
+            Token::Ampersand  => Some(Self::And),
+            Token::Pipe       => Some(Self::Or),
+            _ => None,
+        }
+    }
+    
+    // Same ordering as C, higher binds tighter
+    fn precedence(self) -> u8 {
+        match self {
+            Self::Or  => 1,
+            Self::And => 2,
+            Self::ShiftLeft | Self::ShiftRight => 3,
+            Self::Add | Self::Sub => 4,
+            Self::Mul => 5,
+        }
+    }
+    
+    fn symbol(self) -> &'static str {
+        match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::ShiftLeft  => "<<",
+            Self::ShiftRight => ">>",
+            Self::And => "&",
+            Self::Or  => "|",
+        }
+    }
+    
+    fn apply(self, a: i64, b: i64) -> i64 {
+        match self {
+            Self::Add => a.wrapping_add(b),
+            Self::Sub => a.wrapping_sub(b),
+            Self::Mul => a.wrapping_mul(b),
+            // Shifting everything out gives zero instead of wrapping the shift amount
+            Self::ShiftLeft  => if (0..64).contains(&b) { a << b } else { 0 },
+            Self::ShiftRight => if (0..64).contains(&b) { a >> b } else { 0 },
+            Self::And => a & b,
+            Self::Or  => a | b,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Expression {
+    Number(i64),
+    Symbol(String),
+    Negate(Box<Expression>),
+    Binary(BinaryOp, Box<Expression>, Box<Expression>),
+}
+
+impl Expression {
+    /// Evaluates the expression, failing with the name of the first unknown symbol
+    pub fn evaluate(&self, symbols: &dyn Fn(&str) -> Option<i64>) -> Result<i64, String> {
+        match self {
+            Self::Number(n) => Ok(*n),
+            Self::Symbol(name) => symbols(name).ok_or_else(|| name.clone()),
+            Self::Negate(e) => Ok(e.evaluate(symbols)?.wrapping_neg()),
+            Self::Binary(op, a, b) => Ok(op.apply(a.evaluate(symbols)?, b.evaluate(symbols)?)),
+        }
+    }
+    
+    /// Every symbol the expression refers to
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Self::Number(_) => vec![],
+            Self::Symbol(name) => vec![name.as_str()],
+            Self::Negate(e) => e.symbols(),
+            Self::Binary(_, a, b) => {
+                let mut symbols = a.symbols();
+                symbols.extend(b.symbols());
+                symbols
+            },
+        }
+    }
+    
+    /// Renames every symbol in the expression, stopping at the first error
+    pub fn map_symbols(&mut self, f: &mut dyn FnMut(&str) -> Result<String, String>) -> Result<(), String> {
+        match self {
+            Self::Number(_) => Ok(()),
+            Self::Symbol(name) => {
+                *name = f(name)?;
+                Ok(())
+            },
+            Self::Negate(e) => e.map_symbols(f),
+            Self::Binary(_, a, b) => {
+                a.map_symbols(f)?;
+                b.map_symbols(f)
+            },
+        }
+    }
+}
+
+// Only adds the parentheses that precedence and left associativity need, so parsing the text gives the same tree
+impl core::fmt::Display for Expression {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let binds_looser = |e: &Expression, precedence: u8| matches!(e, Self::Binary(op, ..) if op.precedence() < precedence);
+        match self {
+            Self::Number(n) => write!(f, "{}", n),
+            Self::Symbol(name) => write!(f, "{}", name),
+            Self::Negate(e) if matches!(**e, Self::Binary(..)) => write!(f, "-({})", e),
+            Self::Negate(e) => write!(f, "-{}", e),
+            Self::Binary(op, a, b) => {
+                if binds_looser(a, op.precedence()) {
+                    write!(f, "({})", a)?;
+                } else {
+                    write!(f, "{}", a)?;
+                }
+                write!(f, " {} ", op.symbol())?;
+                if binds_looser(b, op.precedence() + 1) {
+                    write!(f, "({})", b)
+                } else {
+                    write!(f, "{}", b)
+                }
+            },
+        }
+    }
+}
+
+fn peek<'a>(lexer: &Lexer<'a, Token<'a>>) -> Option<Token<'a>> {
+    lexer.clone().next()
+}
+
+/// True if `token` begins an expression rather than a lone immediate or label
+pub fn starts_expression<'a>(token: &Token<'a>, lexer: &Lexer<'a, Token<'a>>) -> bool {
+    match token {
+        Token::LeftParen | Token::Minus => true,
+        Token::Immediate(_) | Token::Ident(_) | Token::Directive(_) | Token::NumericLabel(_) => peek(lexer).and_then(BinaryOp::from_token).is_some(),
+        _ => false,
+    }
+}
+
+/// Parses a numeric literal in decimal, hex or binary without truncation
+pub fn parse_number(literal: &str) -> Result<i64, String> {
+    let parsed = match literal.get(..2) {
+        Some("0x") | Some("0X") => i64::from_str_radix(&literal[2..], 16),
+        Some("0b") | Some("0B") => i64::from_str_radix(&literal[2..], 2),
+        _ => literal.parse::<i64>(),
+    };
+    parsed.map_err(|err| format!("could not parse {}: {}", literal, err))
+}
+
+/// Deepest nesting of parentheses, unary minuses and chained operators, evaluating anything deeper could overflow the stack
+const MAX_NESTING: usize = 256;
+
+fn parse_primary<'a>(token: Option<Token<'a>>, lexer: &mut Lexer<'a, Token<'a>>, depth: usize) -> Result<Expression, String> {
+    if depth > MAX_NESTING {
+        return Err("expression nested too deeply".to_owned());
+    }
+    match token {
+        Some(Token::Immediate(i)) => Ok(Expression::Number(parse_number(i)?)),
+        Some(Token::Ident(name)) => Ok(Expression::Symbol(name.to_owned())),
+        // Local labels keep their dot so the parser can scope them
+        Some(Token::Directive(name)) => Ok(Expression::Symbol(format!(".{}", name))),
+        Some(Token::NumericLabel(name)) => Ok(Expression::Symbol(name.to_owned())),
+        Some(Token::Minus) => {
+            let token = lexer.next();
+            Ok(Expression::Negate(Box::new(parse_primary(token, lexer, depth + 1)?)))
+        },
+        Some(Token::LeftParen) => {
+            let token = lexer.next();
+            let inner = parse_nested(token, lexer, depth + 1)?;
+            match lexer.next() {
+                Some(Token::RightParen) => Ok(inner),
+                Some(token) => Err(format!("expected ')', got: {:?}", token)),
+                None => Err("unclosed '('".to_owned()),
+            }
+        },
+        Some(token) => Err(format!("unexpected token in expression: {:?}", token)),
+        None => Err("expected an expression".to_owned()),
+    }
+}
+
+// Precedence climbing, folds in every operator that binds at least as tight as `min_precedence`
+fn parse_binary<'a>(mut lhs: Expression, min_precedence: u8, lexer: &mut Lexer<'a, Token<'a>>, mut depth: usize) -> Result<Expression, String> {
+    while let Some(op) = peek(lexer).and_then(BinaryOp::from_token) {
+        if op.precedence() < min_precedence {
+            break;
+        }
+        // Every operator folded in puts the tree so far one level deeper
+        depth += 1;
+        if depth > MAX_NESTING {
+            return Err("expression nested too deeply".to_owned());
+        }
+        lexer.next();
+        let token = lexer.next();
+        let mut rhs = parse_primary(token, lexer, depth)?;
+        while let Some(next) = peek(lexer).and_then(BinaryOp::from_token) {
+            if next.precedence() <= op.precedence() {
+                break;
+            }
+            rhs = parse_binary(rhs, op.precedence() + 1, lexer, depth)?;
+        }
+        lhs = Expression::Binary(op, Box::new(lhs), Box::new(rhs));
+    }
+    Ok(lhs)
+}
+
+/// Parses an expression beginning with `first`, leaving the lexer on the token after it
+pub fn parse_expression<'a>(first: Option<Token<'a>>, lexer: &mut Lexer<'a, Token<'a>>) -> Result<Expression, String> {
+    parse_nested(first, lexer, 0)
+}
+
+fn parse_nested<'a>(first: Option<Token<'a>>, lexer: &mut Lexer<'a, Token<'a>>, depth: usize) -> Result<Expression, String> {
+    let lhs = parse_primary(first, lexer, depth)?;
+    parse_binary(lhs, 0, lexer, depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expression::{Expression, parse_expression};
+    use crate::lexer::new_lexer;
+    use crate::prelude::*;
+    
+    fn evaluate(source: &str) -> Result<i64, String> {
+        let mut lexer = new_lexer(source);
+        let first = lexer.next();
+        let expression: Expression = parse_expression(first, &mut lexer)?;
+        assert!(lexer.next().is_none());
+        expression.evaluate(&|name| if name == "label" { Some(0x100) } else { None })
+    }
+    
+    #[test]
+    fn arithmetic() {
+        assert_eq!(evaluate("0x10+2"), Ok(0x12));
+        assert_eq!(evaluate("1<<4"), Ok(16));
+        assert_eq!(evaluate("label-2"), Ok(0xFE));
+        assert_eq!(evaluate("1+2*3"), Ok(7));
+        assert_eq!(evaluate("(1+2)*3"), Ok(9));
+        assert_eq!(evaluate("1<<2+1"), Ok(8));
+        assert_eq!(evaluate("0xF0|0x0F&0x3"), Ok(0xF3));
+        assert_eq!(evaluate("10-4-3"), Ok(3));
+        assert_eq!(evaluate("-2"), Ok(-2));
+        assert_eq!(evaluate("missing+1"), Err("missing".to_owned()));
+        assert!(evaluate("(1+2").is_err());
+    }
+    
+    #[test]
+    fn nesting_limit() {
+        let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(evaluate(&nested(256)), Ok(1));
+        assert_eq!(evaluate(&nested(6000)), Err("expression nested too deeply".to_owned()));
+        assert_eq!(evaluate(&format!("{}1", "-".repeat(30000))), Err("expression nested too deeply".to_owned()));
+        // A chain of operators nests the same way, it's evaluated recursively
+        assert_eq!(evaluate(&format!("0{}", "+1".repeat(200))), Ok(200));
+        assert_eq!(evaluate(&format!("0{}", "+1".repeat(30000))), Err("expression nested too deeply".to_owned()));
+    }
+    
+    #[test]
+    fn display() {
+        for (source, expected) in [("1+2*3", "1 + 2 * 3"), ("(1+2)*3", "(1 + 2) * 3"), ("10-(4-3)", "10 - (4 - 3)"), ("10-4-3", "10 - 4 - 3"), ("-(a+1)", "-(a + 1)"), ("0x10|.x<<2", "16 | .x << 2")] {
+            let mut lexer = new_lexer(source);
+            let first = lexer.next();
+            let text = parse_expression(first, &mut lexer).unwrap().to_string();
+            assert_eq!(text, expected);
+            
+            let mut lexer = new_lexer(&text);
+            let first = lexer.next();
+            assert_eq!(parse_expression(first, &mut lexer).unwrap().to_string(), text);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use crate::assemble_source;
+use crate::parser::{Log, ParseOptions, parse_file};
+use crate::codegen::{AssembleResult, CodegenOptions, assemble_lines};
+
+use std::ffi::{CStr, CString};
+use std::slice;
+use std::fs::File;
+use std::io::Write;
+use std::os::raw::c_char;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Returned by `assemble_x69_buffer` when assembly fails
+pub const X69_ERROR: isize = isize::MIN;
+
+/// Receives every diagnostic, `level` is 0 for warnings and 1 for errors
+pub type LogCallback = extern "C" fn(level: u32, msg: *const c_char);
+
+static LOG_CALLBACK: Mutex<Option<LogCallback>> = Mutex::new(None);
+
+/// Registers a callback for diagnostics, passing null restores printing to stderr
+#[no_mangle]
+pub extern "C" fn x69_set_log_callback(callback: Option<LogCallback>) {
+    *LOG_CALLBACK.lock().unwrap_or_else(|e| e.into_inner()) = callback;
+}
+
+// Prints the logs and returns true if any of them were fatal
+fn print_logs(logs: &[Log]) -> bool {
+    let callback = *LOG_CALLBACK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut fatal = false;
+    for log in logs {
+        match callback {
+            Some(callback) => {
+                let msg = CString::new(log.message().replace('\0', "")).unwrap_or_default();
+                callback(log.is_error() as u32, msg.as_ptr());
+            },
+            None => eprintln!("{}", log),
+        }
+        fatal |= log.is_error();
+    }
+    fatal
+}
+
+unsafe fn c_str_to_path(string: *const c_char) -> Result<PathBuf, Log> {
+    if string.is_null() {
+        return Err(Log::IOError("file name is a null pointer".to_owned(), String::from("[unknown]")));
+    }
+    match CStr::from_ptr(string).to_str() {
+        Ok(s) => Ok(PathBuf::from(s)),
+        Err(err) => Err(Log::IOError(format!("file name is not valid UTF-8: {}", err), String::from("[unknown]"))),
+    }
+}
+
+/// Assembles the file at `input` and writes the binary to `output`.
+/// Returns 0 on success and 1 if any errors were reported.
+///
+/// # Safety
+/// `input` and `output` must either be null or point to valid nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn assemble_x69(input: *const c_char, output: *const c_char) -> i32 {
+    let (input, output) = match (c_str_to_path(input), c_str_to_path(output)) {
+        (Ok(input), Ok(output)) => (input, output),
+        (Err(log), _) | (_, Err(log)) => {
+            print_logs(&[log]);
+            return 1;
+        },
+    };
+    
+    let parse_options = ParseOptions {origin: input, ..Default::default()};
+    
+    let (lines, logs) = parse_file(&parse_options);
+    if print_logs(&logs) {
+        return 1;
+    }
+    
+    let AssembleResult {bytes: asm, logs, ..} = assemble_lines(&lines, &CodegenOptions::default());
+    if print_logs(&logs) {
+        return 1;
+    }
+    
+    let output_name = output.to_string_lossy().into_owned();
+    let result = File::create(&output).and_then(|mut file| file.write_all(&asm));
+    if let Err(err) = result {
+        print_logs(&[Log::IOError(err.to_string(), output_name)]);
+        return 1;
+    }
+    0
+}
+
+/// Assembles `len` bytes of source text at `input` into `out`.
+/// Returns the number of bytes written, or the required capacity if `out` is null.
+/// If `out_cap` is too small nothing is written and the required capacity is returned negated.
+/// Returns `X69_ERROR` if the source could not be assembled.
+///
+/// # Safety
+/// `input` must point to `len` readable bytes and `out` must either be null or point to `out_cap` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn assemble_x69_buffer(input: *const u8, len: usize, out: *mut u8, out_cap: usize) -> isize {
+    if input.is_null() {
+        print_logs(&[Log::IOError("input buffer is a null pointer".to_owned(), String::from("[buffer]"))]);
+        return X69_ERROR;
+    }
+    let source = match std::str::from_utf8(slice::from_raw_parts(input, len)) {
+        Ok(source) => source,
+        Err(err) => {
+            print_logs(&[Log::IOError(format!("input is not valid UTF-8: {}", err), String::from("[buffer]"))]);
+            return X69_ERROR;
+        }
+    };
+    
+    let (asm, logs) = assemble_source(source);
+    if print_logs(&logs) {
+        return X69_ERROR;
+    }
+    
+    if out.is_null() {
+        asm.len() as isize
+    } else if out_cap < asm.len() {
+        -(asm.len() as isize)
+    } else {
+        slice::from_raw_parts_mut(out, asm.len()).copy_from_slice(&asm);
+        asm.len() as isize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ffi::{assemble_x69, assemble_x69_buffer, x69_set_log_callback, X69_ERROR};
+    use std::ffi::CString;
+    use std::os::raw::c_char;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    
+    // The log callback is global, so tests that emit logs must not run concurrently
+    static FFI_LOCK: Mutex<()> = Mutex::new(());
+    
+    #[test]
+    fn missing_input() {
+        let _lock = FFI_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let input = CString::new("this/file/does/not/exist.asm").unwrap();
+        let output = CString::new("this/file/does/not/exist.o").unwrap();
+        assert_eq!(unsafe { assemble_x69(input.as_ptr(), output.as_ptr()) }, 1);
+    }
+    
+    #[test]
+    fn null_input() {
+        let _lock = FFI_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let output = CString::new("out.o").unwrap();
+        assert_eq!(unsafe { assemble_x69(std::ptr::null(), output.as_ptr()) }, 1);
+    }
+    
+    #[test]
+    fn buffer() {
+        let _lock = FFI_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let source = b"add r1, r2\nset r0, 5";
+        let expected = [0b00100101, 0x12, 0b10101001, 0x00, 5];
+        
+        let needed = unsafe { assemble_x69_buffer(source.as_ptr(), source.len(), std::ptr::null_mut(), 0) };
+        assert_eq!(needed, 5);
+        
+        let mut small = [0u8; 2];
+        let result = unsafe { assemble_x69_buffer(source.as_ptr(), source.len(), small.as_mut_ptr(), small.len()) };
+        assert_eq!(result, -5);
+        assert_eq!(small, [0, 0]);
+        
+        let mut out = [0u8; 8];
+        let written = unsafe { assemble_x69_buffer(source.as_ptr(), source.len(), out.as_mut_ptr(), out.len()) };
+        assert_eq!(written, 5);
+        assert_eq!(out[..5], expected);
+        
+        let bad = b"foo r1";
+        assert_eq!(unsafe { assemble_x69_buffer(bad.as_ptr(), bad.len(), out.as_mut_ptr(), out.len()) }, X69_ERROR);
+    }
+    
+    #[test]
+    fn log_callback() {
+        static ERRORS: AtomicUsize = AtomicUsize::new(0);
+        extern "C" fn count(level: u32, _msg: *const c_char) {
+            if level == 1 {
+                ERRORS.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        
+        let _lock = FFI_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        x69_set_log_callback(Some(count));
+        let source = b"foo r1\nset r99, 1";
+        let result = unsafe { assemble_x69_buffer(source.as_ptr(), source.len(), std::ptr::null_mut(), 0) };
+        x69_set_log_callback(None);
+        
+        assert_eq!(result, X69_ERROR);
+        assert_eq!(ERRORS.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::parser::{DataByte, Directive, Line, LineData, Parameters};
+use crate::prelude::*;
+
+// Turns the names the parser gives local and numeric labels back into something it will read the same way
+#[derive(Default)]
+struct Names {
+    scope: String,
+    // Definitions of each numeric label seen so far
+    numeric: HashMap<String, usize>,
+}
+
+impl Names {
+    fn define(&mut self, name: &str) -> String {
+        match name.split_once('@') {
+            Some((label, _)) => {
+                *self.numeric.entry(label.to_owned()).or_insert(0) += 1;
+                label.to_owned()
+            },
+            None => {
+                if !name.contains('.') {
+                    self.scope = name.to_owned();
+                }
+                self.reference(name)
+            },
+        }
+    }
+    
+    fn reference(&self, name: &str) -> String {
+        if let Some((label, index)) = name.split_once('@') {
+            let defined = self.numeric.get(label).copied().unwrap_or(0);
+            let direction = if index.parse::<usize>().is_ok_and(|index| index < defined) { "b" } else { "f" };
+            return format!("{}{}", label, direction);
+        }
+        match name.strip_prefix(self.scope.as_str()) {
+            Some(local) if !self.scope.is_empty() && local.starts_with('.') => local.to_owned(),
+            _ => name.to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FormatOptions {
+    /// Column everything but labels starts at
+    pub tab_width: usize,
+    /// Indents with a single tab instead of `tab_width` spaces
+    pub use_tabs: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {tab_width: 4, use_tabs: false}
+    }
+}
+
+/// Writes parsed lines back out as source that assembles to the same bytes.
+/// Labels sit on their own line and everything else is indented, instructions are lowercase
+/// with `, ` between operands. Trailing comments are kept, but macros, conditionals, repeats and includes are already expanded
+/// and comment-only lines are gone, so this formats the program the assembler saw rather than the file.
+pub fn format_lines(lines: &[Line], options: &FormatOptions) -> String {
+    let indent = if options.use_tabs { "\t".to_owned() } else { " ".repeat(options.tab_width) };
+    let mut names = Names::default();
+    let mut text = String::new();
+    for line in lines {
+        let mut data = line.data.clone();
+        let mut rename = |name: &str| Ok::<_, String>(names.reference(name));
+        match &mut data {
+            LineData::Label(label) => *label = names.define(label),
+            LineData::Instruction {params: Parameters::Label(label), ..} => *label = names.reference(label),
+            LineData::Instruction {params: Parameters::Expression(expr), ..} |
+            LineData::Instruction {params: Parameters::OneRegisterExpression(_, expr), ..} => {
+                let _ = expr.map_symbols(&mut rename);
+            },
+            LineData::Directive(Directive::DB(data_bytes)) => for db in data_bytes {
+                match db {
+                    DataByte::Label(label) | DataByte::LabelByte(label, _) => *label = names.reference(label),
+                    DataByte::Expression(expr) => {
+                        let _ = expr.map_symbols(&mut rename);
+                    },
+                    DataByte::Byte(_) => {},
+                }
+            },
+            _ => {},
+        }
+        
+        match data {
+            LineData::Label(_) | LineData::AbsoluteLabel(..) => text += &data.to_string(),
+            _ => text += &format!("{}{}", indent, data),
+        }
+        if let Some(comment) = &line.comment {
+            text += &format!(" // {}", comment);
+        }
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::{CodegenOptions, assemble_lines};
+    use crate::format::{FormatOptions, format_lines};
+    use crate::parser::parse_raw;
+    use crate::prelude::*;
+    
+    fn format(source: &str) -> String {
+        let (lines, logs) = parse_raw(source, None);
+        assert!(logs.is_empty(), "{:?}", logs);
+        format_lines(&lines, &FormatOptions::default())
+    }
+    
+    fn assemble(source: &str) -> Vec<u8> {
+        let (lines, _) = parse_raw(source, None);
+        assemble_lines(&lines, &CodegenOptions::default()).bytes
+    }
+    
+    #[test]
+    fn canonical() {
+        assert_eq!(format("aDd   r1 ,r2"), "    add r1, r2\n");
+        assert_eq!(format("    add r1, r2\n"), "    add r1, r2\n");
+        assert_eq!(format("START: NOP; Jmp START"), "START:\n    nop\n    jmp START\n");
+    }
+    
+    #[test]
+    fn indentation() {
+        let (lines, _) = parse_raw("loop: add r1, r2 // sum\njmp loop", None);
+        let options = FormatOptions {tab_width: 2, use_tabs: false};
+        assert_eq!(format_lines(&lines, &options), "loop:\n  add r1, r2 // sum\n  jmp loop\n");
+        let options = FormatOptions {tab_width: 8, use_tabs: false};
+        assert_eq!(format_lines(&lines, &options), "loop:\n        add r1, r2 // sum\n        jmp loop\n");
+        let options = FormatOptions {tab_width: 8, use_tabs: true};
+        assert_eq!(format_lines(&lines, &options), "loop:\n\tadd r1, r2 // sum\n\tjmp loop\n");
+    }
+    
+    #[test]
+    fn comments() {
+        assert_eq!(format("add r1,r2   //  sum\nloop: // forever\njmp loop"), "    add r1, r2 // sum\nloop: // forever\n    jmp loop\n");
+        assert_eq!(format("nop; nop // both\n.db \"//\" // slashes"), "    nop\n    nop // both\n    .db 0x2F 0x2F // slashes\n");
+        assert_eq!(format("li r2, 0x1234 // load"), "    set r2, 0x34 // load\n    set r3, 0x12\n");
+    }
+    
+    #[test]
+    fn round_trip() {
+        let source = "
+            start: set r1, 5
+            .loop: dec r1
+            rjmpnz .loop
+            1: add r2, r3, 7
+            jmp 1b
+            jmp 1f
+            1: ldr r4, data + 1
+            li r6, 0x1234
+            .line 0x40
+            data: .db 1 2 data -(start + 1) low(.end) high(.end) \"hi\"
+            .end: ldr r5, 0x1234
+            str r5, 3
+            .global data
+        ";
+        let formatted = format(source);
+        assert_eq!(assemble(&formatted), assemble(source));
+        assert_eq!(format(&formatted), formatted);
+    }
+}
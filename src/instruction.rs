@@ -1,19 +1,23 @@
+use crate::prelude::*;
 use utils::{ToFromString, Iter};
+use serde_json::{Value, json};
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum OperandMode {
     NoParams,                // NOP
     OneRegister,             // CLR R1
     OneOrTwoRegisters,       // INC R1, R1;  INC R1
     OneRegisterAndImmediate, // SET R1, 69
+    OneRegisterAndLongImmediate, // LDR R1, 0x1234
     TwoRegisters,            // LPC R0, R1
     TwoRegistersOrImmediate, // ADD R1, R2;  ADD R1, 69;  ADD R0, R1, 123
+    TwoRegistersAndLongImmediate, // ADDW R0, R1, 0x1234
     
     // LongImmediate, // JMP 1234
     TwoRegistersOrLongImmediate, // JMP 1234;  JMP R1, R2
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum RegisterMap {
     AB,
     BA,
@@ -48,6 +52,7 @@ pub enum Instruction {
     SET,
     STN,
     CMP,
+    ADDW,
     
     // ALU Flag operations
     DNFG,
@@ -99,6 +104,7 @@ const PC:  u8 = 0b00;
 const LR:  u8 = 0b01;
 const SP:  u8 = 0b10;
 const ADR: u8 = 0b11;
+pub(crate) const SPECIAL_REGISTERS: [&str; 4] = ["pc", "lr", "sp", "adr"];
 // 0b01001100
 const fn rw_builder(write: bool, register: u8) -> u8 {
     let mut rw = 0b01001000 | register;
@@ -125,6 +131,40 @@ const fn jump_builder(relative: bool, check_not: bool, alu_flag: u8) -> u8 {
     jmp
 }
 
+// Features an instruction needs from the core, `Cpu::features` says which ones exist
+const BASE: u8 = 0b01;
+const CONDITIONAL_CALL: u8 = 0b10;
+const WIDE_IMMEDIATE: u8 = 0b100;
+
+/// A core the code is assembled for, older cores lack some instructions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Iter)]
+pub enum Cpu {
+    X69V0,
+    #[default]
+    X69,
+}
+
+impl Cpu {
+    /// Name used on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::X69V0 => "x69-v0",
+            Self::X69   => "x69",
+        }
+    }
+    
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::iter().copied().find(|cpu| cpu.name() == name)
+    }
+    
+    pub fn features(&self) -> u8 {
+        match self {
+            Self::X69V0 => BASE,
+            Self::X69   => BASE | CONDITIONAL_CALL | WIDE_IMMEDIATE,
+        }
+    }
+}
+
 const fn call_builder(relative: bool, check_not: bool, alu_flag: u8) -> u8 {
     let mut call = 0b01100001 | alu_flag << 2;
     if relative {
@@ -138,79 +178,159 @@ const fn call_builder(relative: bool, check_not: bool, alu_flag: u8) -> u8 {
 
 impl Instruction {
     #[inline(always)]
-    pub fn assemble_info(&self) -> (u8, OperandMode, RegisterMap) {
+    pub fn assemble_info(&self) -> (u8, OperandMode, RegisterMap, u8) {
         use OperandMode::*;
         use RegisterMap::*;
         match self {
-            Self::NOP => (0b00101001, NoParams,    AB),
-            Self::CLR => (0b00100000, OneRegister, AA),
-            Self::SER => (0b00110000, OneRegister, AA),
-            Self::NOT => (0b00100001, OneOrTwoRegisters,       BA),
-            Self::TWO => (0b00110001, OneOrTwoRegisters,       BA),
-            Self::AND => (0b00100010, TwoRegistersOrImmediate, BA),
-            Self::NND => (0b00110010, TwoRegistersOrImmediate, BA),
-            Self::ORR => (0b00100011, TwoRegistersOrImmediate, BA),
-            Self::NOR => (0b00110011, TwoRegistersOrImmediate, BA),
-            Self::XOR => (0b00100100, TwoRegistersOrImmediate, BA),
-            Self::XNR => (0b00110100, TwoRegistersOrImmediate, BA),
-            Self::ADD => (0b00100101, TwoRegistersOrImmediate, BA),
-            Self::ADC => (0b00110101, TwoRegistersOrImmediate, BA),
-            Self::SUB => (0b00100110, TwoRegistersOrImmediate, BA),
-            Self::SBC => (0b00110110, TwoRegistersOrImmediate, BA),
-            Self::INC => (0b00100111, OneOrTwoRegisters,       BA),
-            Self::DEC => (0b00110111, OneOrTwoRegisters,       BA),
-            Self::MOV => (0b00101000, TwoRegistersOrImmediate, BA),
-            Self::MVN => (0b00111000, TwoRegistersOrImmediate, BA),
-            Self::SET => (0b00101001, OneRegisterAndImmediate, AA),
-            Self::STN => (0b00111001, OneRegisterAndImmediate, AA),
-            Self::CMP => (0b00101010, TwoRegisters,            AB),
+            Self::NOP => (0b00101001, NoParams,    AB, BASE),
+            Self::CLR => (0b00100000, OneRegister, AA, BASE),
+            Self::SER => (0b00110000, OneRegister, AA, BASE),
+            Self::NOT => (0b00100001, OneOrTwoRegisters,       BA, BASE),
+            Self::TWO => (0b00110001, OneOrTwoRegisters,       BA, BASE),
+            Self::AND => (0b00100010, TwoRegistersOrImmediate, BA, BASE),
+            Self::NND => (0b00110010, TwoRegistersOrImmediate, BA, BASE),
+            Self::ORR => (0b00100011, TwoRegistersOrImmediate, BA, BASE),
+            Self::NOR => (0b00110011, TwoRegistersOrImmediate, BA, BASE),
+            Self::XOR => (0b00100100, TwoRegistersOrImmediate, BA, BASE),
+            Self::XNR => (0b00110100, TwoRegistersOrImmediate, BA, BASE),
+            Self::ADD => (0b00100101, TwoRegistersOrImmediate, BA, BASE),
+            Self::ADC => (0b00110101, TwoRegistersOrImmediate, BA, BASE),
+            Self::SUB => (0b00100110, TwoRegistersOrImmediate, BA, BASE),
+            Self::SBC => (0b00110110, TwoRegistersOrImmediate, BA, BASE),
+            Self::INC => (0b00100111, OneOrTwoRegisters,       BA, BASE),
+            Self::DEC => (0b00110111, OneOrTwoRegisters,       BA, BASE),
+            Self::MOV => (0b00101000, TwoRegistersOrImmediate, BA, BASE),
+            Self::MVN => (0b00111000, TwoRegistersOrImmediate, BA, BASE),
+            Self::SET => (0b00101001, OneRegisterAndImmediate, AA, BASE),
+            Self::STN => (0b00111001, OneRegisterAndImmediate, AA, BASE),
+            Self::CMP => (0b00101010, TwoRegisters,            AB, BASE),
+            // Only has the immediate form, `opcode mid lo hi`
+            Self::ADDW => (0b00111010, TwoRegistersAndLongImmediate, BA, WIDE_IMMEDIATE),
             
-            // Will need custom parsing eventually
-            Self::LDR => (0b00010000, OneRegisterAndImmediate, AA),
-            Self::STR => (0b00010001, OneRegisterAndImmediate, AA),
+            // Addresses above 0xFF use the wide form, see `wide_address`
+            Self::LDR => (0b00010000, OneRegisterAndLongImmediate, AA, BASE),
+            Self::STR => (0b00010001, OneRegisterAndLongImmediate, AA, BASE),
             
-            Self::LPC  => (rw_builder(false, PC),  TwoRegisters, AB),
-            Self::SPC  => (rw_builder(true,  PC),  TwoRegistersOrLongImmediate, AB),
-            Self::LLR  => (rw_builder(false, LR),  TwoRegisters,                AB),
-            Self::SLR  => (rw_builder(true,  LR),  TwoRegistersOrLongImmediate, AB),
-            Self::LSP  => (rw_builder(false, SP),  TwoRegisters,                AB),
-            Self::SSP  => (rw_builder(true,  SP),  TwoRegistersOrLongImmediate, AB),
-            Self::LADR => (rw_builder(false, ADR), TwoRegisters,                AB),
-            Self::SADR => (rw_builder(true,  ADR), TwoRegistersOrLongImmediate, AB),
+            Self::LPC  => (rw_builder(false, PC),  TwoRegisters, AB, BASE),
+            Self::SPC  => (rw_builder(true,  PC),  TwoRegistersOrLongImmediate, AB, BASE),
+            Self::LLR  => (rw_builder(false, LR),  TwoRegisters,                AB, BASE),
+            Self::SLR  => (rw_builder(true,  LR),  TwoRegistersOrLongImmediate, AB, BASE),
+            Self::LSP  => (rw_builder(false, SP),  TwoRegisters,                AB, BASE),
+            Self::SSP  => (rw_builder(true,  SP),  TwoRegistersOrLongImmediate, AB, BASE),
+            Self::LADR => (rw_builder(false, ADR), TwoRegisters,                AB, BASE),
+            Self::SADR => (rw_builder(true,  ADR), TwoRegistersOrLongImmediate, AB, BASE),
             
-            Self::RET  => (0b01010000, NoParams, AB),
-            Self::DNFG => (0b01010001, NoParams, AA),
-            Self::ENFG => (0b01010010, NoParams, AA),
-            Self::PUSH => (0b01010011, OneRegister, AA),
-            Self::POP  => (0b01010100, OneRegister, AA),
+            Self::RET  => (0b01010000, NoParams, AB, BASE),
+            Self::DNFG => (0b01010001, NoParams, AA, BASE),
+            Self::ENFG => (0b01010010, NoParams, AA, BASE),
+            Self::PUSH => (0b01010011, OneRegister, AA, BASE),
+            Self::POP  => (0b01010100, OneRegister, AA, BASE),
              
-            Self::JMP    => (0b01000100, TwoRegistersOrLongImmediate, AB),
-            Self::RJMP   => (0b01000110, TwoRegistersOrLongImmediate, AB),
-            Self::JMPZ   => (jump_builder(false, false,  ZERO),  TwoRegistersOrLongImmediate, AB),
-            Self::JMPNZ  => (jump_builder(false, true,   ZERO),  TwoRegistersOrLongImmediate, AB),
-            Self::JMPC   => (jump_builder(false, false,  CARRY), TwoRegistersOrLongImmediate, AB),
-            Self::JMPNC  => (jump_builder(false, true,   CARRY), TwoRegistersOrLongImmediate, AB),
-            Self::RJMPZ  => (jump_builder(true,  false,  ZERO),  TwoRegistersOrLongImmediate, AB),
-            Self::RJMPNZ => (jump_builder(true,  true,   ZERO),  TwoRegistersOrLongImmediate, AB),
-            Self::RJMPC  => (jump_builder(true,  false,  CARRY), TwoRegistersOrLongImmediate, AB),
-            Self::RJMPNC => (jump_builder(true,  true,   CARRY), TwoRegistersOrLongImmediate, AB),
+            Self::JMP    => (0b01000100, TwoRegistersOrLongImmediate, AB, BASE),
+            Self::RJMP   => (0b01000110, TwoRegistersOrLongImmediate, AB, BASE),
+            Self::JMPZ   => (jump_builder(false, false,  ZERO),  TwoRegistersOrLongImmediate, AB, BASE),
+            Self::JMPNZ  => (jump_builder(false, true,   ZERO),  TwoRegistersOrLongImmediate, AB, BASE),
+            Self::JMPC   => (jump_builder(false, false,  CARRY), TwoRegistersOrLongImmediate, AB, BASE),
+            Self::JMPNC  => (jump_builder(false, true,   CARRY), TwoRegistersOrLongImmediate, AB, BASE),
+            Self::RJMPZ  => (jump_builder(true,  false,  ZERO),  TwoRegistersOrLongImmediate, AB, BASE),
+            Self::RJMPNZ => (jump_builder(true,  true,   ZERO),  TwoRegistersOrLongImmediate, AB, BASE),
+            Self::RJMPC  => (jump_builder(true,  false,  CARRY), TwoRegistersOrLongImmediate, AB, BASE),
+            Self::RJMPNC => (jump_builder(true,  true,   CARRY), TwoRegistersOrLongImmediate, AB, BASE),
             
-            Self::CALL    => (0b01000101, TwoRegistersOrLongImmediate, AB),
-            Self::RCALL   => (0b01000111, TwoRegistersOrLongImmediate, AB),
-            Self::CALLZ   => (call_builder(false, false,  ZERO),  TwoRegistersOrLongImmediate, AB),
-            Self::CALLNZ  => (call_builder(false, true,   ZERO),  TwoRegistersOrLongImmediate, AB),
-            Self::CALLC   => (call_builder(false, false,  CARRY), TwoRegistersOrLongImmediate, AB),
-            Self::CALLNC  => (call_builder(false, true,   CARRY), TwoRegistersOrLongImmediate, AB),
-            Self::RCALLZ  => (call_builder(true,  false,  ZERO),  TwoRegistersOrLongImmediate, AB),
-            Self::RCALLNZ => (call_builder(true,  true,   ZERO),  TwoRegistersOrLongImmediate, AB),
-            Self::RCALLC  => (call_builder(true,  false,  CARRY), TwoRegistersOrLongImmediate, AB),
-            Self::RCALLNC => (call_builder(true,  true,   CARRY), TwoRegistersOrLongImmediate, AB),
+            Self::CALL    => (0b01000101, TwoRegistersOrLongImmediate, AB, BASE),
+            Self::RCALL   => (0b01000111, TwoRegistersOrLongImmediate, AB, BASE),
+            Self::CALLZ   => (call_builder(false, false,  ZERO),  TwoRegistersOrLongImmediate, AB, CONDITIONAL_CALL),
+            Self::CALLNZ  => (call_builder(false, true,   ZERO),  TwoRegistersOrLongImmediate, AB, CONDITIONAL_CALL),
+            Self::CALLC   => (call_builder(false, false,  CARRY), TwoRegistersOrLongImmediate, AB, CONDITIONAL_CALL),
+            Self::CALLNC  => (call_builder(false, true,   CARRY), TwoRegistersOrLongImmediate, AB, CONDITIONAL_CALL),
+            Self::RCALLZ  => (call_builder(true,  false,  ZERO),  TwoRegistersOrLongImmediate, AB, CONDITIONAL_CALL),
+            Self::RCALLNZ => (call_builder(true,  true,   ZERO),  TwoRegistersOrLongImmediate, AB, CONDITIONAL_CALL),
+            Self::RCALLC  => (call_builder(true,  false,  CARRY), TwoRegistersOrLongImmediate, AB, CONDITIONAL_CALL),
+            Self::RCALLNC => (call_builder(true,  true,   CARRY), TwoRegistersOrLongImmediate, AB, CONDITIONAL_CALL),
+        }
+    }
+    
+    /// Opcode of the short form, the immediate form sets the top bit
+    pub fn opcode(&self) -> u8 {
+        self.assemble_info().0
+    }
+    
+    pub fn operand_mode(&self) -> OperandMode {
+        self.assemble_info().1
+    }
+    
+    /// Order the operands are stored in the register byte
+    pub fn register_map(&self) -> RegisterMap {
+        self.assemble_info().2
+    }
+    
+    /// Whether the core has every feature this instruction needs
+    pub fn available_on(&self, cpu: Cpu) -> bool {
+        let features = self.assemble_info().3;
+        cpu.features() & features == features
+    }
+    
+    /// Mnemonic in any case, `add` or `ADD`, without allocating an uppercase copy
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::iter().copied().find(|ins| ins.to_str().eq_ignore_ascii_case(name))
+    }
+    
+    /// The closest known mnemonic to a misspelled instruction
+    pub fn closest(name: &str) -> Option<Self> {
+        closest_name(&name.to_uppercase(), Self::iter().map(Self::to_str)).and_then(Self::from_str)
+    }
+    
+    /// Splits a mnemonic that runs into its first register, `addr1` is `ADD` and `r1`
+    pub fn split_register(name: &str) -> Option<(Self, &str)> {
+        (1..name.len()).filter(|i| name.is_char_boundary(*i)).find_map(|i| {
+            let (ins, reg) = name.split_at(i);
+            let is_register = match reg.as_bytes() {
+                [b'r' | b'R', digits @ ..] => !digits.is_empty() && digits.iter().all(u8::is_ascii_digit),
+                _ => SPECIAL_REGISTERS.contains(&reg),
+            };
+            if is_register {
+                Self::from_name(ins).map(|ins| (ins, reg))
+            } else {
+                None
+            }
+        })
+    }
+    
+    /// The name of the special register this instruction loads or stores, if any
+    pub fn special_register(&self) -> Option<&'static str> {
+        let opcode = self.opcode();
+        if opcode & 0b11111000 == rw_builder(false, PC) {
+            Some(SPECIAL_REGISTERS[(opcode & 0b11) as usize])
+        } else {
+            None
         }
     }
     
+    /// Opcode of the four byte form taking a full 16-bit address, `opcode R0, lo, hi`.
+    /// No other instruction uses it, so the first byte alone says which form follows
+    pub fn wide_address(&self) -> Option<u8> {
+        match self {
+            Self::LDR | Self::STR => Some(self.opcode() | 0b10),
+            _ => None,
+        }
+    }
+    
+    /// ALU operations where swapping the operands gives the same result
+    pub fn is_commutative(&self) -> bool {
+        matches!(self, Self::AND | Self::NND | Self::ORR | Self::NOR | Self::XOR | Self::XNR | Self::ADD | Self::ADC)
+    }
+    
+    /// Jumps and calls that encode their target as an offset from the instruction
+    pub fn is_relative(&self) -> bool {
+        let opcode = self.opcode();
+        let is_jump = opcode & 0b11111100 == 0b01000100 || opcode & 0b11100000 == 0b01100000;
+        is_jump && opcode & 0b00000010 != 0
+    }
+    
+    #[cfg(feature = "std")]
     pub fn print_usage(&self) {
         let name = self.to_str();
-        let ops = self.assemble_info().1;
+        let ops = self.operand_mode();
         
         // This exists so that instructions can override their usage printout in special cases
         #[allow(clippy::match_single_binding)]
@@ -218,13 +338,15 @@ impl Instruction {
             _ => match ops {
                 OperandMode::NoParams                => println!("{}",          name),
                 OperandMode::OneRegister             => println!("{}\tR0",      name),
-                OperandMode::OneOrTwoRegisters       => println!("{}\tR0 [R1]", name),
+                OperandMode::OneOrTwoRegisters       => println!("{}\tR0 [R1]\t(R0 alone is R0, R0)", name),
                 OperandMode::OneRegisterAndImmediate => println!("{}\tR0, IM8", name),
+                OperandMode::OneRegisterAndLongImmediate => println!("{}\tR0, IM16", name),
                 OperandMode::TwoRegisters            => println!("{}\tR0, R1",  name),
                 OperandMode::TwoRegistersOrImmediate => {
-                    println!("{}\tR0, IM8", name);
+                    println!("{}\tR0, IM8\t(R0, R0, IM8)", name);
                     println!("{}\tR0, R1 [IM8]", name);
                 },
+                OperandMode::TwoRegistersAndLongImmediate => println!("{}\tR0, R1, IM16", name),
                 OperandMode::TwoRegistersOrLongImmediate => {
                     println!("{}\tR0, R1", name);
                     println!("{}\tIM16", name);
@@ -234,9 +356,126 @@ impl Instruction {
     }
 }
 
+// Edit distance between two strings
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != *cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Finds the candidate within an edit distance of 2.
+/// Ties go to the longest shared prefix and then to whichever candidate came first.
+pub fn closest_name<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    candidates
+        .enumerate()
+        .map(|(i, c)| {
+            let prefix = name.chars().zip(c.chars()).take_while(|(a, b)| a == b).count();
+            ((levenshtein(name, c), core::cmp::Reverse(prefix), i), c)
+        })
+        .filter(|((distance, ..), _)| *distance <= 2)
+        .min_by_key(|(key, _)| *key)
+        .map(|(_, c)| c)
+}
+
+#[cfg(feature = "std")]
 pub fn print_all() {
     println!("Instruction usage:");
     println!("R0: Register (0-15)");
     println!("[]: Optional parameter");
-    Instruction::iter().for_each(Instruction::print_usage);
+    println!("IM8/IM16: Immediate, may be an expression using + - * << >> & | and ()");
+    println!("Special register instructions may name their register first, e.g. LSP sp, R0, R1");
+    Instruction::all().iter().for_each(Instruction::print_usage);
+    
+    println!();
+    println!("Pseudo-instructions:");
+    println!("LI\tR0, IM16\t(SET R0, low; SET R1, high)");
+    println!("PUSH\tR0-R3\t\t(PUSH R0; ...; PUSH R3)");
+    println!("POP\tR0-R3\t\t(POP R3; ...; POP R0)");
+}
+
+/// Every instruction with its encoding details, for editors and other tooling
+pub fn all_json() -> Value {
+    Instruction::all().iter().map(|ins| json!({
+        "mnemonic": ins.to_str(),
+        "opcode": ins.opcode(),
+        "operand_mode": format!("{:?}", ins.operand_mode()),
+        "register_map": format!("{:?}", ins.register_map()),
+        "cpus": Cpu::iter().filter(|cpu| ins.available_on(**cpu)).map(Cpu::name).collect::<Vec<_>>(),
+    })).collect()
+}
+
+#[cfg(feature = "std")]
+pub fn print_json() {
+    println!("{}", all_json());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::{Cpu, Instruction, OperandMode, RegisterMap, all_json, closest_name};
+    
+    #[test]
+    fn closest() {
+        assert_eq!(Instruction::closest("ad").map(|i| i.to_str()), Some("ADD"));
+        assert_eq!(Instruction::closest("nopp").map(|i| i.to_str()), Some("NOP"));
+        assert_eq!(Instruction::closest("rjmpzz").map(|i| i.to_str()), Some("RJMPZ"));
+        assert!(Instruction::closest("hello").is_none());
+        assert_eq!(closest_name("inclde", ["include", "line", "db"].iter().copied()), Some("include"));
+    }
+    
+    #[test]
+    fn metadata() {
+        assert_eq!(Instruction::ADD.opcode(), 0b00100101);
+        assert_eq!(Instruction::ADD.operand_mode(), OperandMode::TwoRegistersOrImmediate);
+        assert_eq!(Instruction::ADD.register_map(), RegisterMap::BA);
+        assert_eq!(Instruction::CMP.register_map(), RegisterMap::AB);
+        
+        assert_eq!(Instruction::all().len(), Instruction::iter().count());
+        assert_eq!(Instruction::all()[0].to_str(), "NOP");
+        assert!(Instruction::all().iter().any(|i| i.to_str() == "RCALLNC"));
+    }
+    
+    #[test]
+    fn wide_opcodes() {
+        for ins in Instruction::all().iter() {
+            if let Some(wide) = ins.wide_address() {
+                assert!(Instruction::all().iter().all(|other| other.opcode() != wide), "{}", ins.to_str());
+            }
+            // Four byte forms need a first byte of their own to be told apart from three byte ones
+            if ins.operand_mode() == OperandMode::TwoRegistersAndLongImmediate {
+                assert!(Instruction::all().iter().all(|other| other.opcode() != ins.opcode() || other.to_str() == ins.to_str()), "{}", ins.to_str());
+            }
+        }
+    }
+    
+    #[test]
+    fn json() {
+        let json = all_json();
+        let nop = json.as_array().unwrap().iter().find(|i| i["mnemonic"] == "NOP").unwrap();
+        assert_eq!(nop["opcode"], 0b00101001);
+        assert_eq!(nop["operand_mode"], "NoParams");
+        assert_eq!(nop["register_map"], "AB");
+        assert_eq!(json.as_array().unwrap().len(), Instruction::all().len());
+        assert_eq!(nop["cpus"], serde_json::json!(["x69-v0", "x69"]));
+    }
+    
+    #[test]
+    fn cpus() {
+        assert_eq!(Cpu::default(), Cpu::X69);
+        assert_eq!(Cpu::from_name("x69-v0"), Some(Cpu::X69V0));
+        assert_eq!(Cpu::from_name("x70"), None);
+        assert!(Instruction::all().iter().all(|ins| ins.available_on(Cpu::X69)));
+        assert!(Instruction::CALL.available_on(Cpu::X69V0));
+        assert!(!Instruction::CALLZ.available_on(Cpu::X69V0));
+        assert!(!Instruction::RCALLNC.available_on(Cpu::X69V0));
+        assert!(!Instruction::ADDW.available_on(Cpu::X69V0));
+    }
 }
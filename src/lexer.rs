@@ -1,34 +1,74 @@
 use logos::Logos;
 
+use crate::prelude::*;
+
+use core::ops::Range;
+
 fn trim_string(string: &'_ str, begin: usize, end: usize) -> &'_ str {
     &string[begin..string.len()-end]
 }
 
 #[derive(Logos, Clone, Copy, Debug, PartialEq)]
 pub enum Token<'a> {
-    #[regex("[_a-zA-Z]\\w*")]
+    // Names and numbers are ASCII only, the Unicode `\w` and `\d` classes would let `é` or `٣` through
+    #[regex("[_a-zA-Z][_a-zA-Z0-9]*")]
     Ident(&'a str),
     
-    #[regex("\\w+:", |lex| trim_string(lex.slice(), 0, 1))]
+    #[regex("\\.?[_a-zA-Z0-9]+:", |lex| trim_string(lex.slice(), 0, 1))]
     Label(&'a str),
     
     #[regex("\"[^\"]*\"", |lex| trim_string(lex.slice(), 1, 1))]
     String(&'a str),
     
-    #[regex("\\.\\w+", |lex| trim_string(lex.slice(), 1, 0))]
+    #[regex("\\.[_a-zA-Z0-9]+", |lex| trim_string(lex.slice(), 1, 0))]
     Directive(&'a str),
     
-    #[regex("(0[xX][\\da-fA-F]+|0[bB][01]+|\\d+)")]
+    #[regex("(0[xX][0-9a-fA-F]+|0[bB][01]+|[0-9]+)")]
     Immediate(&'a str),
     
-    #[regex("r[0-9]+", |lex| trim_string(lex.slice(), 1, 0))]
+    // Reference to the next or previous numeric label, `1f` or `1b`
+    #[regex("[0-9]+[fb]")]
+    NumericLabel(&'a str),
+    
+    #[regex("[rR][0-9]+", |lex| trim_string(lex.slice(), 1, 0))]
     Register(&'a str),
     
     #[token(",")]
     Comma,
     
+    #[token("=")]
+    Equals,
+    
+    #[token("+")]
+    Plus,
+    
+    #[token("-")]
+    Minus,
+    
+    #[token("*")]
+    Star,
+    
+    #[token("<<")]
+    ShiftLeft,
+    
+    #[token(">>")]
+    ShiftRight,
+    
+    #[token("&")]
+    Ampersand,
+    
+    #[token("|")]
+    Pipe,
+    
+    #[token("(")]
+    LeftParen,
+    
+    #[token(")")]
+    RightParen,
+    
     #[error]
-    #[regex("[ \t]+", logos::skip)]
+    // `lines` leaves a `\r` that isn't followed by `\n`, like in `\r\r\n`
+    #[regex("[ \t\r]+", logos::skip)]
     Error,
 }
 
@@ -36,3 +76,25 @@ pub enum Token<'a> {
 pub fn new_lexer(source: &'_ str) -> logos::Lexer<'_, Token<'_>> {
     Token::lexer(source)
 }
+
+/// Every token in `source` with its byte span, for tools that shouldn't depend on logos
+pub fn tokenize(source: &str) -> Vec<(Token<'_>, Range<usize>)> {
+    new_lexer(source).spanned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::{Token, tokenize};
+    
+    #[test]
+    fn spans() {
+        assert_eq!(tokenize("add r1, r2"), vec![
+            (Token::Ident("add"), 0..3),
+            (Token::Register("1"), 4..6),
+            (Token::Comma, 6..7),
+            (Token::Register("2"), 8..10),
+        ]);
+        assert_eq!(tokenize("  .db \"a b\""), vec![(Token::Directive("db"), 2..5), (Token::String("a b"), 6..11)]);
+        assert!(tokenize("").is_empty());
+    }
+}
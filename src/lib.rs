@@ -0,0 +1,154 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg_attr(not(feature = "std"), macro_use)]
+extern crate alloc;
+
+pub mod codegen;
+pub mod expression;
+#[cfg(feature = "std")]
+mod ffi;
+pub mod format;
+pub mod instruction;
+pub mod lexer;
+pub mod parser;
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+mod wasm;
+
+pub use codegen::Register;
+#[cfg(feature = "std")]
+pub use ffi::*;
+pub use instruction::{Instruction, OperandMode, RegisterMap};
+pub use parser::{Directive, Line, LineData, Parameters};
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+pub use wasm::assemble_wasm;
+
+use parser::{Log, ParseOptions, parse_raw};
+use codegen::{AssembleResult, CodegenOptions, Endian, assemble_lines};
+use serde_json::{Value, json};
+#[cfg(feature = "std")]
+use std::path::PathBuf;
+
+// What the std prelude brings in, so every module reads the same with or without std
+pub(crate) mod prelude {
+    pub use alloc::borrow::ToOwned;
+    pub use alloc::boxed::Box;
+    pub use alloc::string::{String, ToString};
+    pub use alloc::vec::Vec;
+    #[cfg(feature = "std")]
+    pub use std::collections::{HashMap, HashSet};
+    // There is no random hasher without std, the ordered collections stand in
+    #[cfg(not(feature = "std"))]
+    pub use alloc::collections::{BTreeMap as HashMap, BTreeSet as HashSet};
+}
+use prelude::*;
+
+/// Parse and codegen options in one place for assembling from code.
+/// `Assembler::new().define("SIZE", 16).fill(0xFF).assemble_str(source)`
+#[derive(Clone, Debug, Default)]
+pub struct Assembler {
+    #[cfg(feature = "std")]
+    include_paths: Vec<PathBuf>,
+    defines: HashMap<String, u16>,
+    codegen: CodegenOptions,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Directory searched for includes that aren't next to the including file
+    #[cfg(feature = "std")]
+    pub fn include_path(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.include_paths.push(dir.into());
+        self
+    }
+    
+    /// Same as `-D NAME=VALUE`
+    pub fn define(mut self, name: &str, value: u16) -> Self {
+        self.defines.insert(name.to_owned(), value);
+        self
+    }
+    
+    pub fn endian(mut self, endian: Endian) -> Self {
+        self.codegen.endian = endian;
+        self
+    }
+    
+    /// Byte used to pad gaps left by `.line`
+    pub fn fill(mut self, byte: u8) -> Self {
+        self.codegen.fill = byte;
+        self
+    }
+    
+    /// Parses and assembles an in-memory source string.
+    /// Codegen is skipped if parsing produced any errors.
+    pub fn assemble_str(&self, source: &str) -> (Vec<u8>, Vec<Log>) {
+        // Built fresh every time, so includes seen by one call aren't skipped by the next
+        let options = ParseOptions {
+            #[cfg(feature = "std")]
+            include_paths: self.include_paths.clone(),
+            defines: self.defines.clone(),
+            ..Default::default()
+        };
+        let (lines, mut logs) = parse_raw(source, Some(&options));
+        if logs.iter().any(Log::is_error) {
+            return (vec![], logs);
+        }
+        let AssembleResult {bytes: asm, logs: asm_logs, ..} = assemble_lines(&lines, &self.codegen);
+        logs.extend(asm_logs);
+        (asm, logs)
+    }
+}
+
+/// `Assembler::assemble_str` with every option left at its default
+pub fn assemble_source(source: &str) -> (Vec<u8>, Vec<Log>) {
+    Assembler::new().assemble_str(source)
+}
+
+/// `assemble_source` as `{ bytes: [...], diagnostics: [...] }`, with each log from `Log::to_json`
+pub fn assemble_json(source: &str) -> Value {
+    let (bytes, logs) = assemble_source(source);
+    json!({
+        "bytes": bytes,
+        "diagnostics": logs.iter().map(Log::to_json).collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Assembler, assemble_json, assemble_source};
+    use crate::codegen::Endian;
+    use serde_json::json;
+    
+    // Run with `--no-default-features --lib` to check the core still builds on `alloc` alone
+    #[test]
+    fn in_memory() {
+        let (bytes, logs) = assemble_source("start: set r0, 5\nadd r0, r1\njmp start");
+        assert!(logs.is_empty());
+        assert_eq!(bytes, [0b10101001, 0x00, 5, 0b00100101, 0x01, 0b11000100, 0x00, 0x00]);
+    }
+    
+    #[test]
+    fn builder() {
+        let assembler = Assembler::new().define("COUNT", 5).endian(Endian::Big).fill(0xFF);
+        let (bytes, logs) = assembler.assemble_str("set r0, COUNT\njmp end\n.line 10\nend: nop");
+        assert!(logs.is_empty(), "{:?}", logs);
+        assert_eq!(bytes, [0b10101001, 0x00, 5, 0b11000100, 0x00, 10, 0xFF, 0xFF, 0xFF, 0xFF, 0b00101001, 0x00]);
+    }
+    
+    #[test]
+    fn json() {
+        let result = assemble_json("nop\nadd r1, 0x1FF");
+        assert_eq!(result["bytes"], json!([0b00101001, 0x00, 0b10100101, 0x11, 0xFF]));
+        assert_eq!(result["diagnostics"], json!([{
+            "level": "warning",
+            "code": "truncation",
+            "message": "immediate 0x1FF exceeds 8 bits for ADD; truncated to 0xFF",
+            "origin": "[unknown]",
+            "line": 2,
+            "column": 9,
+        }]));
+        assert_eq!(assemble_json("frob")["diagnostics"][0]["level"], "error");
+    }
+}
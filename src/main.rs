@@ -1,26 +1,71 @@
-mod codegen;
-mod instruction;
-mod lexer;
-mod parser;
-
 use clap::{AppSettings, App, Arg};
-use parser::{Log, ParseOptions, parse_file};
-use codegen::assemble_lines;
+use assembler::expression::parse_number;
+use assembler::format::{FormatOptions, format_lines};
+use assembler::instruction::{self, Cpu};
+use assembler::parser::{Code, Log, ParseOptions, parse_file};
+use assembler::codegen::{AssembleResult, CodegenOptions, Endian, assemble_lines, crc16, disassemble, hexdump, write_line_map, write_map, write_object};
 
+use std::collections::HashMap;
 use std::io::Write;
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::process;
 
 fn print_logs_abort(logs: &[Log]) {
-    let mut fatal = false;
+    if print_logs(logs, "aborting") {
+        process::exit(1);
+    }
+}
+// Prints every log and a summary saying what happens next, true if any of them is an error
+fn print_logs(logs: &[Log], outcome: &str) -> bool {
+    // Source files are only read back in when a log needs to show its line
+    let mut sources = HashMap::<String, Option<Vec<String>>>::new();
+    let (mut errors, mut warnings) = (0, 0);
     for log in logs {
-        eprintln!("{}", log);
-        fatal |= log.is_error();
+        let source_line = match log {
+            Log::Warning(line, Some(_), _, _, origin) | Log::Error(line, Some(_), _, _, origin) => {
+                sources.entry(origin.to_string())
+                    .or_insert_with(|| std::fs::read_to_string(origin.as_str()).ok().map(|s| s.lines().map(String::from).collect()))
+                    .as_ref()
+                    .and_then(|lines| lines.get(*line))
+            },
+            _ => None,
+        };
+        match source_line {
+            Some(source_line) => eprintln!("{}", log.display_with_source(source_line)),
+            None => eprintln!("{}", log),
+        }
+        if log.is_error() {
+            errors += 1;
+        } else {
+            warnings += 1;
+        }
     }
-    if fatal {
-        eprintln!("Aborting due to previous errors...");
-        process::exit(1);
+    
+    let count = |n: usize, what: &str| format!("{} {}{}", n, what, if n == 1 { "" } else { "s" });
+    let warnings = match warnings {
+        0 => None,
+        n => Some(format!("{} emitted", count(n, "warning"))),
+    };
+    if errors > 0 {
+        let (color, reset) = if cfg!(feature = "no_color") { ("", "") } else { ("\x1b[1;31m", "\x1b[0m") };
+        match warnings {
+            Some(warnings) => eprintln!("{}error:{} {} due to {}; {}", color, reset, outcome, count(errors, "previous error"), warnings),
+            None => eprintln!("{}error:{} {} due to {}", color, reset, outcome, count(errors, "previous error")),
+        }
+        return true;
+    }
+    if let Some(warnings) = warnings {
+        eprintln!("{}", warnings);
+    }
+    false
+}
+// Warnings about the command line itself have no source position
+fn warn(message: String, origin: &str) {
+    if cfg!(feature = "no_color") {
+        eprintln!("WARNING: {}: {}", origin, message);
+    } else {
+        eprintln!("\x1b[1;33mWARNING:\x1b[0m {}: {}", origin, message);
     }
 }
 fn make_log_and_abort(message: String, origin: &Path) -> ! {
@@ -39,9 +84,10 @@ fn main() {
         .about("The official x69 assembler!")
         .version(format!("v{}",env!("CARGO_PKG_VERSION")).as_str())
         .setting(color)
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::new("FILE")
             // .required(true)
-            .required_unless_present("list")
+            .required_unless_present_any(["list", "list-json"])
             .about("Input file to be assembled")
             .takes_value(true))
         .arg(Arg::new("output")
@@ -49,35 +95,302 @@ fn main() {
             .long("output")
             .value_name("FILE")
             .takes_value(true))
+        .arg(Arg::new("define")
+            .about("Defines a symbol for conditional assembly and immediates, VALUE defaults to 1")
+            .short('D')
+            .long("define")
+            .value_name("NAME[=VALUE]")
+            .takes_value(true)
+            .multiple_occurrences(true))
+        .arg(Arg::new("cpu")
+            .about("Core to assemble for, instructions it lacks are errors")
+            .long("cpu")
+            .value_name("NAME")
+            .possible_values(&Cpu::iter().map(Cpu::name).collect::<Vec<_>>())
+            .default_value(Cpu::default().name())
+            .takes_value(true))
+        .arg(Arg::new("endian")
+            .about("Byte order of 16-bit immediates and addresses")
+            .long("endian")
+            .value_name("ORDER")
+            .possible_values(&Endian::iter().map(Endian::name).collect::<Vec<_>>())
+            .default_value(Endian::default().name())
+            .takes_value(true))
+        .arg(Arg::new("relocatable")
+            .about("Leaves undefined symbols for a linker instead of failing")
+            .long("relocatable"))
+        .arg(Arg::new("emit")
+            .about("Output format, a raw binary or an object file for linking")
+            .long("emit")
+            .value_name("FORMAT")
+            .possible_values(&["bin", "obj"])
+            .default_value("bin")
+            .takes_value(true))
+        .arg(Arg::new("warning")
+            .about("Enables an optional warning (unused-labels, align), or silences one with no-CODE")
+            .short('W')
+            .value_name("WARNING")
+            .takes_value(true)
+            .multiple_occurrences(true))
+        .arg(Arg::new("lenient-operands")
+            .about("Accepts an immediate before the register for instructions whose operands commute")
+            .long("lenient-operands"))
+        .arg(Arg::new("werror")
+            .about("Treats all warnings as errors")
+            .long("werror"))
+        .arg(Arg::new("emit-partial")
+            .about("Still writes the output when there are errors, unresolved values are left as 0xDEAD")
+            .long("emit-partial"))
+        .arg(Arg::new("check")
+            .about("Assembles and reports diagnostics without writing any output, for linting")
+            .long("check")
+            .conflicts_with("emit-partial"))
+        .arg(Arg::new("quiet")
+            .about("Prints errors only, warnings are dropped")
+            .short('q')
+            .long("quiet"))
+        .arg(Arg::new("append-checksum")
+            .about("Appends a little endian CRC-16 of the assembled bytes and any padding to the output")
+            .long("append-checksum"))
+        .arg(Arg::new("debug-lines")
+            .about("Writes a table mapping output addresses to source lines for debuggers")
+            .long("debug-lines")
+            .value_name("FILE")
+            .takes_value(true))
+        .arg(Arg::new("map")
+            .about("Writes the address of every label and the entry point as text")
+            .long("map")
+            .value_name("FILE")
+            .takes_value(true))
+        .arg(Arg::new("fill")
+            .about("Byte used to pad gaps in the output")
+            .long("fill")
+            .value_name("BYTE")
+            .default_value("0")
+            .takes_value(true))
+        .arg(Arg::new("rom-size")
+            .about("Pads the output to exactly SIZE bytes with the fill byte, larger programs are an error. An appended checksum is the last two bytes")
+            .long("rom-size")
+            .value_name("SIZE")
+            .takes_value(true))
+        .arg(Arg::new("dump")
+            .about("Prints a hexdump of the output to stderr")
+            .long("dump"))
+        .arg(Arg::new("disassemble")
+            .about("Prints the instructions in an assembled binary instead of assembling")
+            .long("disassemble"))
         .arg(Arg::new("list")
             .about("Lists all available instructions")
             .long("list"))
+        .arg(Arg::new("list-json")
+            .about("Lists all available instructions as JSON")
+            .long("list-json"))
+        .subcommand(App::new("fmt")
+            .about("Prints the file in canonical formatting, with macros and includes expanded")
+            .arg(Arg::new("FILE")
+                .required(true)
+                .about("Input file to be formatted")
+                .takes_value(true))
+            .arg(Arg::new("tab-width")
+                .about("Column instructions and directives are indented to")
+                .long("tab-width")
+                .value_name("WIDTH")
+                .default_value("4")
+                .takes_value(true))
+            .arg(Arg::new("use-tabs")
+                .about("Indents with a tab instead of spaces")
+                .long("use-tabs")))
         .get_matches();
     
     if arg_parse.is_present("list") {
         instruction::print_all();
         return;
     }
+    if arg_parse.is_present("list-json") {
+        instruction::print_json();
+        return;
+    }
+    
+    if let Some(fmt) = arg_parse.subcommand_matches("fmt") {
+        let parse_options = ParseOptions {
+            origin: PathBuf::from(fmt.value_of("FILE").unwrap()),
+            ..Default::default()
+        };
+        let tab_width = fmt.value_of("tab-width").unwrap();
+        let tab_width = match tab_width.parse() {
+            Ok(tab_width) => tab_width,
+            Err(_) => {
+                print_logs_abort(&[Log::IOError(format!("invalid tab width: {}", tab_width), String::from("--tab-width"))]);
+                0
+            },
+        };
+        let format_options = FormatOptions {
+            tab_width,
+            use_tabs: fmt.is_present("use-tabs"),
+        };
+        let (lines, logs) = parse_file(&parse_options);
+        print_logs_abort(&logs);
+        print!("{}", format_lines(&lines, &format_options));
+        return;
+    }
     
     let file_name = Path::new(arg_parse.value_of("FILE").unwrap());
     
+    if arg_parse.is_present("disassemble") {
+        match std::fs::read(file_name) {
+            Ok(bytes) => disassemble(&bytes).iter().for_each(|line| println!("{}", line)),
+            Err(err) => make_log_and_abort(err.to_string(), file_name),
+        }
+        return;
+    }
+    
+    let mut defines = HashMap::new();
+    for define in arg_parse.values_of("define").into_iter().flatten() {
+        let (name, value) = match define.find('=') {
+            Some(i) => (&define[..i], &define[i + 1..]),
+            None => (define, "1"),
+        };
+        match parse_number(value) {
+            Ok(value) if (0..=u16::MAX as i64).contains(&value) => {
+                defines.insert(name.to_owned(), value as u16);
+            },
+            _ => print_logs_abort(&[Log::IOError(format!("invalid value for {}: {}", name, value), String::from("--define"))]),
+        }
+    }
+    
+    // `-W NAME` enables an optional warning, `-Wno-CODE` silences warnings with that code
+    let mut warn_unused_labels = false;
+    let mut warn_align = false;
+    let mut allow = vec![];
+    for warning in arg_parse.values_of("warning").into_iter().flatten() {
+        match warning.strip_prefix("no-") {
+            Some(name) => match Code::from_name(name) {
+                Some(code) => allow.push(code),
+                None => warn(format!("unknown warning code: {}", name), "-W"),
+            },
+            None if warning == "unused-labels" => warn_unused_labels = true,
+            None if warning == "align" => warn_align = true,
+            None => print_logs_abort(&[Log::IOError(format!("unknown warning: {}", warning), String::from("-W"))]),
+        }
+    }
+    
     let parse_options = ParseOptions {
         origin: file_name.to_owned(),
-        include_paths: vec![]
+        defines,
+        warnings_as_errors: arg_parse.is_present("werror"),
+        allow: allow.clone(),
+        lenient_operands: arg_parse.is_present("lenient-operands"),
+        ..Default::default()
+    };
+    
+    // With `--werror` the warnings are errors by now and still shown
+    let quiet = arg_parse.is_present("quiet");
+    let shown = |logs: Vec<Log>| -> Vec<Log> {
+        logs.into_iter().filter(|log| !quiet || log.is_error()).collect()
+    };
+    
+    // With `--emit-partial` the errors are only reported once everything that could be assembled is
+    let partial = arg_parse.is_present("emit-partial");
+    let (lines, parse_logs) = parse_file(&parse_options);
+    let parse_logs = match partial {
+        true => parse_logs,
+        false => {
+            print_logs_abort(&shown(parse_logs));
+            vec![]
+        },
     };
     
-    let (lines, logs) = parse_file(&parse_options);
-    print_logs_abort(&logs);
+    let fill = arg_parse.value_of("fill").unwrap();
+    let fill = match parse_number(fill) {
+        Ok(value) if (0..=u8::MAX as i64).contains(&value) => value as u8,
+        _ => {
+            print_logs_abort(&[Log::IOError(format!("invalid fill byte: {}", fill), String::from("--fill"))]);
+            0
+        },
+    };
+    
+    let codegen_options = CodegenOptions {
+        relocatable: arg_parse.is_present("relocatable"),
+        warn_unused_labels,
+        warn_align,
+        fill,
+        warnings_as_errors: arg_parse.is_present("werror"),
+        allow,
+        cpu: arg_parse.value_of("cpu").and_then(Cpu::from_name).unwrap_or_default(),
+        endian: arg_parse.value_of("endian").and_then(Endian::from_name).unwrap_or_default(),
+    };
+    let AssembleResult {bytes: mut asm, link_info, symbols, absolute, line_map, entry, logs} = assemble_lines(&lines, &codegen_options);
+    let failed = match partial {
+        true => print_logs(&shown(parse_logs.into_iter().chain(logs).collect()), "writing partial output"),
+        false => {
+            print_logs_abort(&shown(logs));
+            false
+        },
+    };
+    
+    // The checksum takes the last two bytes of the rom, so padding stops short of them
+    let append_checksum = arg_parse.is_present("append-checksum");
+    let checksum_width = if append_checksum { 2 } else { 0 };
+    
+    if let Some(rom_size) = arg_parse.value_of("rom-size") {
+        let rom_size = match parse_number(rom_size) {
+            Ok(value) if value >= 0 => value as usize,
+            _ => {
+                print_logs_abort(&[Log::IOError(format!("invalid rom size: {}", rom_size), String::from("--rom-size"))]);
+                0
+            },
+        };
+        let size = asm.len() + checksum_width;
+        if size > rom_size {
+            let message = format!("program is {} bytes, {} over the rom size of {}", size, size - rom_size, rom_size);
+            print_logs_abort(&[Log::IOError(message, String::from("--rom-size"))]);
+        }
+        asm.resize(rom_size - checksum_width, fill);
+    }
+    
+    // Appended after assembly and padding so labels can never point at it and it covers the whole image
+    if append_checksum {
+        let crc = crc16(&asm);
+        asm.extend_from_slice(&crc.to_le_bytes());
+    }
     
-    let (asm, logs) = assemble_lines(&lines);
-    print_logs_abort(&logs);
+    if arg_parse.is_present("dump") {
+        eprint!("{}", hexdump(&asm));
+    }
+    
+    // Errors have already aborted, so everything checked out
+    if arg_parse.is_present("check") {
+        return;
+    }
     
     let output_name = arg_parse.value_of("output").map(PathBuf::from).unwrap_or_else(|| file_name.with_extension("o"));
     let mut output = match File::create(&output_name) {
         Ok(file) => file,
         Err(err) => make_log_and_abort(err.to_string(), &output_name),
     };
-    if let Err(err) = output.write_all(&asm) {
+    let result = match arg_parse.value_of("emit") {
+        Some("obj") => write_object(&mut output, &asm, &link_info),
+        _ => output.write_all(&asm),
+    };
+    if let Err(err) = result {
         make_log_and_abort(err.to_string(), &output_name);
     }
+    
+    if let Some(debug_name) = arg_parse.value_of("debug-lines").map(PathBuf::from) {
+        let result = File::create(&debug_name).and_then(|mut file| write_line_map(&mut file, &line_map));
+        if let Err(err) = result {
+            make_log_and_abort(err.to_string(), &debug_name);
+        }
+    }
+    
+    if let Some(map_name) = arg_parse.value_of("map").map(PathBuf::from) {
+        let result = File::create(&map_name).and_then(|mut file| write_map(&mut file, &symbols, &absolute, entry.as_ref()));
+        if let Err(err) = result {
+            make_log_and_abort(err.to_string(), &map_name);
+        }
+    }
+    
+    if failed {
+        process::exit(1);
+    }
 }
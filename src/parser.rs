@@ -1,35 +1,182 @@
 use crate::lexer::Token;
 use crate::codegen::Register;
-use crate::instruction::{Instruction, OperandMode};
+use crate::expression::{Expression, parse_expression, parse_number, starts_expression};
+use crate::instruction::{Instruction, OperandMode, SPECIAL_REGISTERS, closest_name};
+use crate::prelude::*;
+use serde_json::{Value, json};
+use utils::Iter;
 
-use std::fs::File;
-use std::io::Read;
-use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use alloc::borrow::Cow;
+use alloc::sync::Arc;
+use core::ops::Range;
+#[cfg(feature = "std")]
+use std::{cell::RefCell, collections::hash_map::DefaultHasher, fs::File, hash::{Hash, Hasher}, io::Read, path::{Path, PathBuf}, rc::Rc};
 
+/// What kind of problem a log reports, so tools can match on it without parsing the message
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Iter)]
+pub enum Code {
+    /// Anything malformed that has no more specific code
+    Syntax,
+    UnknownInstruction,
+    UnknownDirective,
+    RegisterOutOfRange,
+    MalformedRegister,
+    TrailingComma,
+    /// An operand that repeats what a shorter form already does
+    RedundantOperand,
+    /// An immediate written before the register
+    OperandOrder,
+    ImmediateTruncated,
+    InvalidImmediate,
+    EmptyDb,
+    /// A label in `.db` takes two bytes for the full address
+    DbLabelWidth,
+    /// A `.db` value that doesn't fit in a byte
+    DbRange,
+    /// Conditionals, macros and repeats
+    Preprocessor,
+    UnresolvedSymbol,
+    DuplicateSymbol,
+    UnusedLabel,
+    RegionOverlap,
+    /// A `.line` that lands exactly where the code already ends
+    EmptyPadding,
+    Alignment,
+    AddressSpace,
+    Io,
+}
+
+impl Code {
+    /// Short name used on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Syntax => "syntax",
+            Self::UnknownInstruction => "unknown-instruction",
+            Self::UnknownDirective => "unknown-directive",
+            Self::RegisterOutOfRange => "register-range",
+            Self::MalformedRegister => "malformed-register",
+            Self::TrailingComma => "trailing-comma",
+            Self::RedundantOperand => "redundant-operand",
+            Self::OperandOrder => "operand-order",
+            Self::ImmediateTruncated => "truncation",
+            Self::InvalidImmediate => "invalid-immediate",
+            Self::EmptyDb => "empty-db",
+            Self::DbLabelWidth => "db-label-width",
+            Self::DbRange => "db-range",
+            Self::Preprocessor => "preprocessor",
+            Self::UnresolvedSymbol => "unresolved-symbol",
+            Self::DuplicateSymbol => "duplicate-symbol",
+            Self::UnusedLabel => "unused-labels",
+            Self::RegionOverlap => "overlap",
+            Self::EmptyPadding => "empty-padding",
+            Self::Alignment => "alignment",
+            Self::AddressSpace => "address-space",
+            Self::Io => "io",
+        }
+    }
+    
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::iter().copied().find(|code| code.name() == name)
+    }
+}
+
+/// Warnings and errors carry the line number, if known the byte range of the offending token, and what kind of problem it is
 #[derive(Clone, Debug)]
 pub enum Log {
-    Warning(usize, String, Rc<String>),
-    Error(usize, String, Rc<String>),
+    Warning(usize, Option<Range<usize>>, Code, String, Arc<String>),
+    Error(usize, Option<Range<usize>>, Code, String, Arc<String>),
     IOError(String, String),
 }
 
+// Formats `line` or `line:col` with both counting from 1
+fn position(line: usize, span: &Option<Range<usize>>) -> String {
+    match span {
+        Some(span) => format!("{}:{}", line + 1, span.start + 1),
+        None => format!("{}", line + 1),
+    }
+}
+
 impl Log {
     pub fn is_error(&self) -> bool { matches!(self, Self::Error(..) | Self::IOError(..)) }
+    
+    pub fn code(&self) -> Code {
+        match self {
+            Self::Warning(_, _, code, ..) | Self::Error(_, _, code, ..) => *code,
+            Self::IOError(..) => Code::Io,
+        }
+    }
+    
+    /// Reclassifies a warning as an error, other logs are returned untouched
+    pub fn into_error(self) -> Self {
+        match self {
+            Self::Warning(line, span, code, msg, origin) => Self::Error(line, span, code, msg, origin),
+            log => log,
+        }
+    }
+    
+    /// The log without its severity prefix or any coloring
+    pub fn message(&self) -> String {
+        match self {
+            Self::Warning(line, span, _, msg, origin) | Self::Error(line, span, _, msg, origin) => format!("{}:{}: {}", origin, position(*line, span), msg),
+            Self::IOError(msg, origin) => format!("{}: {}", origin, msg),
+        }
+    }
+    
+    /// Renders the log followed by its source line with the offending token underlined
+    pub fn display_with_source(&self, source_line: &str) -> String {
+        let span = match self {
+            Self::Warning(_, Some(span), ..) | Self::Error(_, Some(span), ..) => span,
+            _ => return self.to_string(),
+        };
+        let (color, reset) = match (cfg!(feature = "no_color"), self.is_error()) {
+            (true, _) => ("", ""),
+            (false, true) => ("\x1b[1;31m", "\x1b[0m"),
+            (false, false) => ("\x1b[1;33m", "\x1b[0m"),
+        };
+        // Keep tabs so the underline lines up with the source
+        let padding: String = source_line.get(..span.start).unwrap_or(source_line).chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let underline = format!("^{}", "~".repeat(span.len().saturating_sub(1)));
+        format!("{}\n    {}\n    {}{}{}{}", self, source_line, padding, color, underline, reset)
+    }
+    
+    /// The log as an object for tools, positions count from 1 and are null when unknown
+    pub fn to_json(&self) -> Value {
+        let level = if self.is_error() { "error" } else { "warning" };
+        match self {
+            Self::Warning(line, span, _, msg, origin) | Self::Error(line, span, _, msg, origin) => json!({
+                "level": level,
+                "code": self.code().name(),
+                "message": msg,
+                "origin": origin.as_str(),
+                "line": line + 1,
+                "column": span.as_ref().map(|span| span.start + 1),
+            }),
+            Self::IOError(msg, origin) => json!({
+                "level": level,
+                "code": self.code().name(),
+                "message": msg,
+                "origin": origin,
+                "line": null,
+                "column": null,
+            }),
+        }
+    }
 }
 
-impl std::fmt::Display for Log {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Log {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             #[cfg(feature = "no_color")]
-            Self::Warning(line, msg, origin) => write!(f, "WARNING: {}:{}: {}", origin, line + 1, msg),
+            Self::Warning(line, span, _, msg, origin) => write!(f, "WARNING: {}:{}: {}", origin, position(*line, span), msg),
             #[cfg(not(feature = "no_color"))]
-            Self::Warning(line, msg, origin) => write!(f, "\x1b[1;33mWARNING:\x1b[0m {}:{}: {}", origin, line + 1, msg),
+            Self::Warning(line, span, _, msg, origin) => write!(f, "\x1b[1;33mWARNING:\x1b[0m {}:{}: {}", origin, position(*line, span), msg),
             
             #[cfg(feature = "no_color")]
-            Self::Error(line, msg, origin) => write!(f, "ERROR:   {}:{}: {}", origin, line + 1, msg),
+            Self::Error(line, span, _, msg, origin) => write!(f, "ERROR:   {}:{}: {}", origin, position(*line, span), msg),
             #[cfg(not(feature = "no_color"))]
-            Self::Error(line, msg, origin) => write!(f, "\x1b[1;31mERROR:\x1b[0m   {}:{}: {}", origin, line + 1, msg),
+            Self::Error(line, span, _, msg, origin) => write!(f, "\x1b[1;31mERROR:\x1b[0m   {}:{}: {}", origin, position(*line, span), msg),
             
             #[cfg(feature = "no_color")]
             Self::IOError(msg, origin) => write!(f, "ERROR:   {}: {}", origin, msg),
@@ -45,29 +192,61 @@ impl std::fmt::Display for Log {
 pub enum Parameters {
     None,
     Label(String),
+    // 16-bit immediate that references symbols
+    Expression(Expression),
     LongImmediate(u16),
     OneRegister(Register),
     TwoRegisters(Register, Register),
     OneRegisterImmediate(Register, u8),
-    TwoRegistersImmedaite(Register, Register, u8),
+    OneRegisterLongImmediate(Register, u16),
+    // Address that references symbols
+    OneRegisterExpression(Register, Expression),
+    TwoRegistersImmediate(Register, Register, u8),
+    TwoRegistersLongImmediate(Register, Register, u16),
+}
+
+/// Which byte of a 16-bit address to keep
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteSelect {
+    Low,
+    High,
 }
 
 #[derive(Clone, Debug)]
 pub enum DataByte {
     Label(String),
+    Expression(Expression),
     Byte(u8),
+    /// `low(label)` or `high(label)`, a single byte of the label's address
+    LabelByte(String, ByteSelect),
 }
 
 #[derive(Clone, Debug)]
 pub enum Directive {
     Line(u16),
     DB(Vec<DataByte>),
+    /// Exports a label from the object
+    Global(String),
+    /// Label execution starts at, noted in the map file
+    Entry(String),
+}
+
+/// Instructions that expand into one or more real instructions
+#[derive(Clone, Debug)]
+pub enum Pseudo {
+    // LI R0, IM16 loads the low byte into R0 and the high byte into R1
+    LoadImmediate(Register, u16),
+    // PUSH R1-R4 and POP R1-R4 run once for each register in the range
+    RegisterRange(Instruction, Register, Register),
 }
 
 #[derive(Clone, Debug)]
 pub enum LineData {
     Label(String),
+    /// `vga = 0x8000`, a label at a fixed address that takes no space
+    AbsoluteLabel(String, u16),
     Directive(Directive),
+    Pseudo(Pseudo),
     Instruction {
         name: Instruction,
         params: Parameters,
@@ -76,16 +255,184 @@ pub enum LineData {
 
 #[derive(Clone, Debug)]
 pub struct Line {
-    pub origin: Rc<String>,
+    pub origin: Arc<String>,
     pub line: usize,
     pub data: LineData,
+    /// Text of a trailing `//` comment, kept on the last line its statement produced
+    pub comment: Option<String>,
+}
+
+// Source text for the parsed forms, symbols are written with their internal names, see `format::format_lines`
+impl core::fmt::Display for Parameters {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::None => Ok(()),
+            Self::Label(label) => write!(f, "{}", label),
+            Self::Expression(expr) => write!(f, "{}", expr),
+            Self::LongImmediate(value) => write!(f, "0x{:04X}", value),
+            Self::OneRegister(a) => write!(f, "r{}", a.as_u8()),
+            Self::TwoRegisters(a, b) => write!(f, "r{}, r{}", a.as_u8(), b.as_u8()),
+            Self::OneRegisterImmediate(a, value) => write!(f, "r{}, 0x{:02X}", a.as_u8(), value),
+            Self::OneRegisterLongImmediate(a, value) => write!(f, "r{}, 0x{:04X}", a.as_u8(), value),
+            Self::OneRegisterExpression(a, expr) => write!(f, "r{}, {}", a.as_u8(), expr),
+            Self::TwoRegistersImmediate(a, b, value) => write!(f, "r{}, r{}, 0x{:02X}", a.as_u8(), b.as_u8(), value),
+            Self::TwoRegistersLongImmediate(a, b, value) => write!(f, "r{}, r{}, 0x{:04X}", a.as_u8(), b.as_u8(), value),
+        }
+    }
+}
+
+impl core::fmt::Display for DataByte {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Label(label) => write!(f, "{}", label),
+            // A leading `-` would otherwise subtract from the previous item
+            Self::Expression(expr) => write!(f, "({})", expr),
+            Self::Byte(byte) => write!(f, "0x{:02X}", byte),
+            Self::LabelByte(label, ByteSelect::Low) => write!(f, "low({})", label),
+            Self::LabelByte(label, ByteSelect::High) => write!(f, "high({})", label),
+        }
+    }
+}
+
+impl core::fmt::Display for Directive {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Line(offset) => write!(f, ".line 0x{:04X}", offset),
+            Self::DB(data_bytes) => {
+                write!(f, ".db")?;
+                data_bytes.iter().try_for_each(|db| write!(f, " {}", db))
+            },
+            Self::Global(name) => write!(f, ".global {}", name),
+            Self::Entry(name) => write!(f, ".entry {}", name),
+        }
+    }
+}
+
+impl core::fmt::Display for LineData {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Label(label) => write!(f, "{}:", label),
+            Self::AbsoluteLabel(label, address) => write!(f, "{} = 0x{:04X}", label, address),
+            Self::Directive(directive) => write!(f, "{}", directive),
+            Self::Pseudo(Pseudo::LoadImmediate(a, value)) => write!(f, "li r{}, 0x{:04X}", a.as_u8(), value),
+            Self::Pseudo(Pseudo::RegisterRange(name, a, b)) => write!(f, "{} r{}-r{}", name.to_str().to_ascii_lowercase(), a.as_u8(), b.as_u8()),
+            Self::Instruction {name, params: Parameters::None} => write!(f, "{}", name.to_str().to_ascii_lowercase()),
+            Self::Instruction {name, params} => write!(f, "{} {}", name.to_str().to_ascii_lowercase(), params),
+        }
+    }
+}
+
+const DIRECTIVES: [&str; 15] = ["include", "line", "db", "global", "entry", "macro", "endm", "if", "ifdef", "ifndef", "else", "endif", "repeat", "endr", "end"];
+
+// Deepest a macro may expand other macros
+const MACRO_DEPTH_LIMIT: usize = 32;
+
+// Most statements `.repeat` blocks may produce in one file, nested blocks count towards it together.
+// Every instruction takes at least two bytes, so a block past this can't fit in the 16-bit address space
+const REPEAT_STATEMENT_LIMIT: usize = 0x10000;
+
+/// Symbol holding the current iteration inside a `.repeat` block
+pub const REPEAT_INDEX: &str = "REPEAT_INDEX";
+
+/// Parsed files kept between runs, keyed by a hash of their content and everything else that changes how they parse
+#[cfg(feature = "std")]
+#[derive(Default)]
+pub struct ParseCache {
+    entries: HashMap<u64, CacheEntry>,
+    // Content hash of every file read through the cache
+    file_hashes: HashMap<PathBuf, u64>,
+    /// Number of files that were taken from the cache instead of being parsed
+    pub hits: usize,
+}
+
+#[cfg(feature = "std")]
+struct CacheEntry {
+    // Files pulled in by includes, which have to be unchanged too
+    dependencies: Vec<(PathBuf, u64)>,
+    lines: Vec<Line>,
+    logs: Vec<Log>,
 }
 
+#[cfg(feature = "std")]
+fn hash_of<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(feature = "std")]
+impl ParseCache {
+    // Whether all includes of an entry are unchanged and could be included again
+    fn is_fresh(entry: &CacheEntry, included: &HashSet<PathBuf>) -> bool {
+        entry.dependencies.iter().all(|(path, hash)| {
+            !included.contains(path) && std::fs::read_to_string(path).ok().map(|contents| hash_of(&contents)) == Some(*hash)
+        })
+    }
+}
+
+/// Without the `std` feature there are no files, so only the options that don't deal with them are left
 pub struct ParseOptions {
+    #[cfg(feature = "std")]
     pub origin: PathBuf,
+    #[cfg(feature = "std")]
     pub include_paths: Vec<PathBuf>,
+    pub defines: HashMap<String, u16>,
+    /// Reports every warning as an error
+    pub warnings_as_errors: bool,
+    /// Warnings with these codes are dropped
+    pub allow: Vec<Code>,
+    /// Canonical paths of every file parsed so far, shared with includes so a file is only parsed once
+    #[cfg(feature = "std")]
+    pub included: Rc<RefCell<HashSet<PathBuf>>>,
+    /// Canonical paths of the files that include this one, outermost first, to report include cycles
+    #[cfg(feature = "std")]
+    pub include_stack: Vec<PathBuf>,
+    /// Parses the includes of this file on their own threads, nested includes are still parsed in order
+    #[cfg(feature = "std")]
+    pub parallel_includes: bool,
+    /// Reuses earlier results for files that haven't changed, shared with includes
+    #[cfg(feature = "std")]
+    pub cache: Rc<RefCell<ParseCache>>,
+    /// Accepts `add 5, r1` for instructions whose operands commute, with a warning
+    pub lenient_operands: bool,
+    /// Longer lines are reported and skipped without being lexed
+    pub max_line_length: usize,
+    /// Longer tokens are reported and their line is skipped
+    pub max_token_length: usize,
+}
+
+impl Default for ParseOptions {
+    /// Options for a source with no file behind it, override `origin` when parsing one
+    fn default() -> Self {
+        ParseOptions {
+            #[cfg(feature = "std")]
+            origin: PathBuf::from("[unknown]"),
+            #[cfg(feature = "std")]
+            include_paths: vec![],
+            defines: HashMap::new(),
+            warnings_as_errors: false,
+            allow: vec![],
+            #[cfg(feature = "std")]
+            included: Default::default(),
+            #[cfg(feature = "std")]
+            include_stack: vec![],
+            #[cfg(feature = "std")]
+            parallel_includes: false,
+            #[cfg(feature = "std")]
+            cache: Default::default(),
+            lenient_operands: false,
+            max_line_length: MAX_LINE_LENGTH,
+            max_token_length: MAX_TOKEN_LENGTH,
+        }
+    }
 }
 
+/// Default for `ParseOptions::max_line_length`, a line can't meaningfully fill more than the address space
+pub const MAX_LINE_LENGTH: usize = 0x10000;
+/// Default for `ParseOptions::max_token_length`
+pub const MAX_TOKEN_LENGTH: usize = 0x1000;
+
+#[cfg(feature = "std")]
 fn pathbuf_to_string(path: &Path) -> String {
     match path.to_owned().into_os_string().into_string() {
         Ok(string) => string,
@@ -93,18 +440,647 @@ fn pathbuf_to_string(path: &Path) -> String {
     }
 }
 
+#[cfg(feature = "std")]
 pub fn parse_file(options: &ParseOptions) -> (Vec<Line>, Vec<Log>) {
+    // Headers included more than once are skipped, cycles have already been reported by the `.include`
+    if let Ok(path) = options.origin.canonicalize() {
+        if !options.included.borrow_mut().insert(path) {
+            return (vec![], vec![]);
+        }
+    }
+    
     let mut file = match File::open(&options.origin) {
         Ok(file) => file,
         Err(err) => return (vec![], vec![Log::IOError(err.to_string(), pathbuf_to_string(&options.origin))])
     };
     
-    let mut contents = String::new();
-    if let Err(err) = file.read_to_string(&mut contents) {
+    let mut bytes = Vec::new();
+    if let Err(err) = file.read_to_end(&mut bytes) {
         return (vec![], vec![Log::IOError(err.to_string(), pathbuf_to_string(&options.origin))])
     }
+    // `read_to_string` doesn't say where the bad byte is, which is the only useful part
+    let contents = match String::from_utf8(bytes) {
+        Ok(contents) => contents,
+        Err(err) => {
+            let message = format!("input is not valid UTF-8 at byte {}", err.utf8_error().valid_up_to());
+            return (vec![], vec![Log::IOError(message, pathbuf_to_string(&options.origin))])
+        },
+    };
+    
+    // Lines carry their origin and depend on every option, so all of it goes into the key
+    let mut defines: Vec<_> = options.defines.iter().collect();
+    defines.sort();
+    let allow: Vec<_> = options.allow.iter().map(|code| code.name()).collect();
+    let limits = (options.max_line_length, options.max_token_length);
+    let key = hash_of(&(&options.origin, &contents, defines, options.warnings_as_errors, allow, limits, options.lenient_operands, &options.include_paths, &options.include_stack));
+    {
+        let mut cache = options.cache.borrow_mut();
+        if let Ok(path) = options.origin.canonicalize() {
+            cache.file_hashes.insert(path, hash_of(&contents));
+        }
+        let hit = cache.entries.get(&key).filter(|entry| ParseCache::is_fresh(entry, &options.included.borrow()));
+        if let Some(entry) = hit {
+            let result = (entry.lines.clone(), entry.logs.clone());
+            options.included.borrow_mut().extend(entry.dependencies.iter().map(|(path, _)| path.clone()));
+            cache.hits += 1;
+            return result;
+        }
+    }
+    
+    let before = options.included.borrow().clone();
+    let (lines, logs) = parse_raw(&contents, Some(options));
+    
+    let mut cache = options.cache.borrow_mut();
+    let dependencies: Option<Vec<_>> = options.included.borrow().difference(&before)
+        .map(|path| cache.file_hashes.get(path).map(|hash| (path.clone(), *hash)))
+        .collect();
+    // Files parsed on other threads never had their hash recorded
+    if let Some(dependencies) = dependencies {
+        cache.entries.insert(key, CacheEntry {dependencies, lines: lines.clone(), logs: logs.clone()});
+    }
+    (lines, logs)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ImmError {
+    /// Hex or binary literal with significant digits past the width, holds the truncated value
+    Truncated(u64),
+    Invalid(String),
+}
+
+/// Parses an immediate literal into `bits` bits.
+/// Hex and binary literals keep their lowest digits when too long, leading zeros don't count.
+/// Decimal literals that don't fit are invalid.
+pub fn parse_immediate(text: &str, bits: u32) -> Result<u64, ImmError> {
+    let (digits, radix, digit_bits) = match text.get(..2) {
+        Some("0x") | Some("0X") => (&text[2..], 16, 4),
+        Some("0b") | Some("0B") => (&text[2..], 2, 1),
+        _ => {
+            let max = u64::MAX >> (64 - bits);
+            return match text.parse::<u64>() {
+                Ok(value) if value <= max => Ok(value),
+                Ok(_) => Err(ImmError::Invalid("number too large to fit in target type".to_owned())),
+                Err(err) => Err(ImmError::Invalid(err.to_string())),
+            };
+        },
+    };
+    if digits.is_empty() {
+        return Err(ImmError::Invalid("no digits after the prefix".to_owned()));
+    }
+    
+    let significant = digits.trim_start_matches('0');
+    let kept = (bits / digit_bits) as usize;
+    let (dropped, kept) = significant.split_at(significant.len().saturating_sub(kept));
+    let value = match kept {
+        "" => 0,
+        kept => u64::from_str_radix(kept, radix).map_err(|err| ImmError::Invalid(err.to_string()))?,
+    };
+    match dropped {
+        "" => Ok(value),
+        _ => Err(ImmError::Truncated(value)),
+    }
+}
+
+// Whether an identifier was probably meant to be a register
+fn looks_like_register(name: &str) -> bool {
+    name.len() <= 4 && name.starts_with(['r', 'R'])
+}
+
+// Mangles a local label into its scope, `.loop` under `func` becomes `func.loop`
+fn scoped(name: &str, scope: &str) -> String {
+    if name.starts_with('.') {
+        format!("{}{}", scope, name)
+    } else {
+        name.to_owned()
+    }
+}
+
+// Numeric labels can be defined any number of times, each definition gets its own name
+// and `1b` or `1f` refer to the closest one before or after
+#[derive(Default)]
+struct NumericLabels {
+    defined: HashMap<String, usize>,
+    // Forward references are checked once every definition has been seen
+    forward: Vec<(String, usize, usize)>,
+}
+
+impl NumericLabels {
+    fn is_numeric(label: &str) -> bool {
+        label.bytes().all(|b| b.is_ascii_digit())
+    }
+    
+    fn define(&mut self, label: &str) -> String {
+        let count = self.defined.entry(label.to_owned()).or_insert(0);
+        *count += 1;
+        format!("{}@{}", label, *count - 1)
+    }
+    
+    fn reference(&mut self, reference: &str, line: usize) -> Result<String, String> {
+        let (label, direction) = reference.split_at(reference.len() - 1);
+        let count = self.defined.get(label).copied().unwrap_or(0);
+        if direction == "f" {
+            self.forward.push((label.to_owned(), count, line));
+            Ok(format!("{}@{}", label, count))
+        } else if count > 0 {
+            Ok(format!("{}@{}", label, count - 1))
+        } else {
+            Err(format!("numeric label {} has no previous definition", label))
+        }
+    }
+}
+
+// Describes a `0x` or `0b` literal without valid digits, the lexer splits or rejects these
+fn prefix_error(text: &str) -> Option<String> {
+    match text.as_bytes() {
+        [b'0', b'x' | b'X' | b'b' | b'B'] => Some(format!("incomplete numeric literal '{}'", text)),
+        [b'0', b'x' | b'X' | b'b' | b'B', ..] => Some(format!("invalid numeric literal '{}'", text)),
+        _ => None,
+    }
+}
+
+// Names only take ASCII, a letter like `é` is otherwise an unexpected character with no hint why
+fn non_ascii_error(text: &str) -> Option<String> {
+    let c = text.chars().find(|c| !c.is_ascii() && c.is_alphanumeric())?;
+    Some(format!("non-ASCII character '{}' in name, only ASCII letters, digits and _ are allowed", c))
+}
+
+// Splits a line into `;` separated statements along with their byte offsets
+fn split_statements(line: &str) -> Vec<(usize, &str)> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => {
+                statements.push((start, &line[start..i]));
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    statements.push((start, &line[start..]));
+    statements
+}
+
+// Splits off a `//` comment that isn't inside a string, an empty comment counts as none
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '/' if !in_string && line[i..].starts_with("//") => {
+                let comment = line[i + 2..].trim();
+                return (&line[..i], if comment.is_empty() { None } else { Some(comment) });
+            },
+            _ => {},
+        }
+    }
+    (line, None)
+}
+
+// Span of the last lexed token relative to the start of its line
+fn token_span<'a>(lexer: &logos::Lexer<'a, Token<'a>>, column: Option<usize>) -> Option<Range<usize>> {
+    column.map(|column| lexer.span().start + column..lexer.span().end + column)
+}
+
+// A single statement ready to be parsed, `column` is None for text produced by macro expansion.
+// Statements straight from the source borrow it, only joined or expanded text is owned
+struct Statement<'s> {
+    line: usize,
+    column: Option<usize>,
+    text: Cow<'s, str>,
+    // Trailing comment of the source line, only on its last statement
+    comment: Option<Cow<'s, str>>,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+// The outermost `.repeat` being recorded, each body statement keeps its `.repeat` nesting depth
+struct Repeat<'s> {
+    count: usize,
+    line: usize,
+    depth: usize,
+    body: Vec<(Statement<'s>, usize)>,
+}
+
+// Replaces whole identifiers outside of strings that name a macro parameter
+fn substitute(text: &str, params: &[String], args: &[String]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut in_string = false;
+    let mut word = String::new();
+    for c in text.chars().chain(core::iter::once('\n')) {
+        if !in_string && (c.is_ascii_alphanumeric() || c == '_') {
+            word.push(c);
+            continue;
+        }
+        match params.iter().position(|p| *p == word) {
+            Some(i) => out.push_str(&args[i]),
+            None => out.push_str(&word),
+        }
+        word.clear();
+        if c == '"' {
+            in_string = !in_string;
+        }
+        out.push(c);
+    }
+    out.pop();
+    out
+}
+
+// Turns source text into statements, collecting `.macro` definitions and expanding their invocations,
+// unrolling `.repeat` blocks and dropping every statement in an inactive conditional branch
+struct Preprocessor<'a, 's> {
+    defines: &'a HashMap<String, u16>,
+    origin: &'a Arc<String>,
+    logs: &'a mut Vec<Log>,
+    statements: Vec<Statement<'s>>,
+    macros: HashMap<String, Macro>,
+    // Name, macro and line of the `.macro` currently being recorded
+    defining: Option<(String, Macro, usize)>,
+    // Each nested conditional is (active, seen .else, opening line)
+    conditions: Vec<(bool, bool, usize)>,
+    repeating: Option<Repeat<'s>>,
+    // Statements produced by unrolling so far, across every `.repeat` block
+    unrolled: usize,
+    macro_depth: usize,
+    // Set by `.end`, everything after it in the file is ignored
+    ended: bool,
+}
+
+impl<'a, 's> Preprocessor<'a, 's> {
+    fn error(&mut self, line: usize, span: Option<Range<usize>>, msg: String) {
+        self.logs.push(Log::Error(line, span, Code::Preprocessor, msg, self.origin.clone()));
+    }
+    
+    // Evaluates a constant expression from the rest of the statement
+    fn constant<'t>(&mut self, line: usize, column: Option<usize>, lexer: &mut logos::Lexer<'t, Token<'t>>, directive: &str) -> Option<i64> {
+        let first = lexer.next();
+        let expr = match parse_expression(first, lexer) {
+            Ok(expr) => expr,
+            Err(err) => {
+                self.error(line, token_span(lexer, column), err);
+                return None;
+            },
+        };
+        if lexer.clone().next().is_some() {
+            lexer.next();
+            let msg = format!("unexpected token after .{} expression: {}", directive, lexer.slice());
+            self.error(line, token_span(lexer, column), msg);
+            return None;
+        }
+        let defines = self.defines;
+        match expr.evaluate(&|name| defines.get(name).map(|v| *v as i64)) {
+            Ok(value) => Some(value),
+            Err(symbol) => {
+                self.error(line, None, format!("{} is not defined", symbol));
+                None
+            },
+        }
+    }
+    
+    fn statement(&mut self, statement: Statement<'s>) {
+        if self.ended {
+            return;
+        }
+        let Statement {line, column, ref text, ..} = statement;
+        let mut lexer = crate::lexer::new_lexer(text);
+        let lowercase;
+        let first = match lexer.next() {
+            Some(Token::Directive(dir)) => {
+                lowercase = dir.to_ascii_lowercase();
+                Some(Token::Directive(&lowercase))
+            },
+            token => token,
+        };
+        
+        // Repeated blocks are recorded untouched so that everything inside runs once per iteration
+        if let Some(repeat) = &mut self.repeating {
+            match first {
+                Some(Token::Directive("repeat")) => {
+                    repeat.body.push((statement, repeat.depth));
+                    repeat.depth += 1;
+                },
+                Some(Token::Directive("endr")) if repeat.depth == 0 => {
+                    let repeat = self.repeating.take().unwrap();
+                    'unroll: for i in 0..repeat.count {
+                        let index = [i.to_string()];
+                        for (statement, depth) in &repeat.body {
+                            self.unrolled += 1;
+                            if self.unrolled > REPEAT_STATEMENT_LIMIT {
+                                // Only the innermost block reports it, the blocks around it just stop
+                                if self.unrolled == REPEAT_STATEMENT_LIMIT + 1 {
+                                    let msg = format!("repeat opened on line {} unrolls to more than {} statements", repeat.line + 1, REPEAT_STATEMENT_LIMIT);
+                                    self.error(repeat.line, None, msg);
+                                }
+                                break 'unroll;
+                            }
+                            // Nested blocks substitute their own index when they are unrolled
+                            let text = match depth {
+                                0 => Cow::Owned(substitute(&statement.text, &[REPEAT_INDEX.to_owned()], &index)),
+                                _ => statement.text.clone(),
+                            };
+                            let column = if text == statement.text { statement.column } else { None };
+                            self.statement(Statement {line: statement.line, column, text, comment: statement.comment.clone()});
+                        }
+                    }
+                },
+                Some(Token::Directive("endr")) => {
+                    repeat.depth -= 1;
+                    repeat.body.push((statement, repeat.depth));
+                },
+                _ => repeat.body.push((statement, repeat.depth)),
+            }
+            return;
+        }
+        
+        // Macro bodies are recorded untouched and processed on every invocation
+        if let Some((name, mac, _)) = &mut self.defining {
+            match first {
+                Some(Token::Directive("endm")) => {
+                    let name = core::mem::take(name);
+                    let mac = core::mem::replace(mac, Macro {params: vec![], body: vec![]});
+                    self.macros.insert(name, mac);
+                    self.defining = None;
+                },
+                Some(Token::Directive("macro")) => {
+                    self.error(line, token_span(&lexer, column), "macros cannot be defined inside other macros".to_owned());
+                },
+                _ => mac.body.push(text.to_string()),
+            }
+            return;
+        }
+        
+        let active = self.conditions.iter().all(|c| c.0);
+        match first {
+            Some(Token::Directive(dir @ "ifdef")) | Some(Token::Directive(dir @ "ifndef")) => {
+                let defined = match lexer.next() {
+                    Some(Token::Ident(name)) => self.defines.contains_key(name),
+                    token => {
+                        self.error(line, token_span(&lexer, column), format!(".{} expects a symbol name, got: {:?}", dir, token));
+                        false
+                    },
+                };
+                self.conditions.push((defined == (dir == "ifdef"), false, line));
+            },
+            Some(Token::Directive("if")) => {
+                // Inactive branches may reference symbols that don't exist
+                let value = match active {
+                    true => self.constant(line, column, &mut lexer, "if").unwrap_or(0),
+                    false => 0,
+                };
+                self.conditions.push((value != 0, false, line));
+            },
+            Some(Token::Directive("else")) => match self.conditions.last_mut() {
+                Some((active, seen_else @ false, _)) => {
+                    *active = !*active;
+                    *seen_else = true;
+                },
+                Some((.., opened)) => {
+                    let msg = format!("duplicate .else for the conditional opened on line {}", *opened + 1);
+                    self.error(line, token_span(&lexer, column), msg);
+                },
+                None => self.error(line, token_span(&lexer, column), ".else without a matching .if".to_owned()),
+            },
+            Some(Token::Directive("endif")) => {
+                if self.conditions.pop().is_none() {
+                    self.error(line, token_span(&lexer, column), ".endif without a matching .if".to_owned());
+                }
+            },
+            _ if !active => {},
+            
+            Some(Token::Directive("repeat")) => {
+                let count = match self.constant(line, column, &mut lexer, "repeat") {
+                    Some(count) if (0..=0x10000).contains(&count) => count as usize,
+                    Some(count) => {
+                        self.error(line, None, format!("repeat count {} must be between 0 and 65536", count));
+                        0
+                    },
+                    None => 0,
+                };
+                self.repeating = Some(Repeat {count, line, depth: 0, body: vec![]});
+            },
+            Some(Token::Directive("endr")) => {
+                self.error(line, token_span(&lexer, column), ".endr without a matching .repeat".to_owned());
+            },
+            Some(Token::Directive("macro")) => {
+                let name = match lexer.next() {
+                    Some(Token::Ident(name)) if Instruction::from_name(name).is_some() => {
+                        self.error(line, token_span(&lexer, column), format!("macro {} has the same name as an instruction", name));
+                        name
+                    },
+                    Some(Token::Ident(name)) => name,
+                    Some(token) => return self.error(line, token_span(&lexer, column), format!("expected a macro name, got: {:?}", token)),
+                    None => return self.error(line, token_span(&lexer, column), "expected a macro name".to_owned()),
+                };
+                let mut params = Vec::new();
+                loop {
+                    match lexer.next() {
+                        Some(Token::Ident(param)) => params.push(param.to_owned()),
+                        Some(Token::Comma) => {},
+                        Some(token) => self.error(line, token_span(&lexer, column), format!("expected a macro parameter name, got: {:?}", token)),
+                        None => break,
+                    }
+                }
+                self.defining = Some((name.to_owned(), Macro {params, body: vec![]}, line));
+            },
+            Some(Token::Directive("endm")) => {
+                self.error(line, token_span(&lexer, column), ".endm without a matching .macro".to_owned());
+            },
+            Some(Token::Directive("end")) => {
+                if lexer.next().is_some() {
+                    self.error(line, token_span(&lexer, column), format!("unexpected token after .end: {}", lexer.slice()));
+                }
+                // The `.endif`s after it are ignored too, so the file ending closes every conditional
+                self.conditions.clear();
+                self.ended = true;
+            },
+            _ => self.invoke(statement),
+        }
+    }
+    
+    // Splits a line of source into statements, a line joined from several has no columns that match the source
+    fn source_line(&mut self, line: usize, text: Cow<'s, str>, comment: Option<Cow<'s, str>>, columns: bool) {
+        let statements = split_statements(&text);
+        let last = statements.len() - 1;
+        for (i, (offset, statement)) in statements.into_iter().enumerate() {
+            let comment = if i == last { comment.clone() } else { None };
+            let column = if columns { Some(offset) } else { None };
+            let statement = match text {
+                Cow::Borrowed(text) => Cow::Borrowed(&text[offset..offset + statement.len()]),
+                Cow::Owned(_) => Cow::Owned(statement.to_owned()),
+            };
+            self.statement(Statement {line, column, text: statement, comment});
+        }
+    }
     
-    parse_raw(&contents, Some(options))
+    // Pushes the statement, expanding it first if it invokes a macro
+    fn invoke(&mut self, statement: Statement<'s>) {
+        let mut lexer = crate::lexer::new_lexer(&statement.text);
+        let mut token = lexer.next();
+        let mut rest = 0;
+        
+        // A label may come before the invocation
+        if let Some(Token::Label(_)) = token {
+            rest = lexer.span().end;
+            token = lexer.next();
+        }
+        let name = match token {
+            Some(Token::Ident(name)) if self.macros.contains_key(name) => name,
+            _ => return self.statements.push(statement),
+        };
+        if self.macro_depth >= MACRO_DEPTH_LIMIT {
+            return self.error(statement.line, None, format!("macro {} exceeds the expansion depth limit of {}", name, MACRO_DEPTH_LIMIT));
+        }
+        
+        let args: Vec<String> = match statement.text[lexer.span().end..].trim() {
+            "" => vec![],
+            args => args.split(',').map(|a| a.trim().to_owned()).collect(),
+        };
+        let mac = &self.macros[name];
+        if args.len() != mac.params.len() {
+            let msg = format!("macro {} expects {} arguments, got {}", name, mac.params.len(), args.len());
+            return self.error(statement.line, None, msg);
+        }
+        let body: Vec<String> = mac.body.iter().map(|body| substitute(body, &mac.params, &args)).collect();
+        
+        if rest > 0 {
+            let text = match statement.text {
+                Cow::Borrowed(text) => Cow::Borrowed(&text[..rest]),
+                Cow::Owned(ref text) => Cow::Owned(text[..rest].to_owned()),
+            };
+            self.statements.push(Statement {line: statement.line, column: statement.column, text, comment: statement.comment.clone()});
+        }
+        self.macro_depth += 1;
+        for text in body {
+            self.statement(Statement {line: statement.line, column: None, text: Cow::Owned(text), comment: None});
+        }
+        self.macro_depth -= 1;
+    }
+}
+
+fn preprocess<'s>(source: &'s str, defines: &HashMap<String, u16>, limits: (usize, usize), logs: &mut Vec<Log>, origin: &Arc<String>) -> Vec<Statement<'s>> {
+    let (max_line_length, max_token_length) = limits;
+    let mut preprocessor = Preprocessor {
+        defines,
+        origin,
+        logs,
+        statements: Vec::new(),
+        macros: HashMap::new(),
+        defining: None,
+        conditions: Vec::new(),
+        repeating: None,
+        unrolled: 0,
+        macro_depth: 0,
+        ended: false,
+    };
+    // First line, text and comments of a line continued with `\`
+    let mut continued: Option<(usize, String, Vec<String>)> = None;
+    for (line, text) in source.lines().enumerate() {
+        if preprocessor.ended {
+            break;
+        }
+        // NUL is valid UTF-8 but would lex into an error for every byte, one error per line is plenty
+        if let Some(column) = text.find('\0') {
+            let message = "unexpected NUL byte, this looks like a binary file".to_owned();
+            preprocessor.logs.push(Log::Error(line, Some(column..column + 1), Code::Syntax, message, origin.clone()));
+            continue;
+        }
+        if text.len() > max_line_length {
+            let message = format!("line is {} bytes long, the limit is {}", text.len(), max_line_length);
+            preprocessor.logs.push(Log::Error(line, None, Code::Syntax, message, origin.clone()));
+            continue;
+        }
+        // Only a line longer than the limit can hold a token that is
+        if text.len() > max_token_length {
+            let mut lexer = crate::lexer::new_lexer(text);
+            if let Some(span) = core::iter::from_fn(|| lexer.next().map(|_| lexer.span())).find(|span| span.len() > max_token_length) {
+                let message = format!("token is {} bytes long, the limit is {}", span.len(), max_token_length);
+                preprocessor.logs.push(Log::Error(line, Some(span), Code::Syntax, message, origin.clone()));
+                continue;
+            }
+        }
+        let (text, comment) = split_comment(text);
+        // A `\` at the end joins the next line on, each line can still have its own comment
+        if let Some(text) = text.trim_end().strip_suffix('\\') {
+            let (_, joined, comments) = continued.get_or_insert_with(|| (line, String::new(), Vec::new()));
+            joined.push_str(text);
+            joined.push(' ');
+            comments.extend(comment.map(str::to_owned));
+            continue;
+        }
+        match continued.take() {
+            Some((first, mut joined, mut comments)) => {
+                joined.push_str(text);
+                comments.extend(comment.map(str::to_owned));
+                preprocessor.source_line(first, Cow::Owned(joined), Some(comments.join(" ")).filter(|c| !c.is_empty()).map(Cow::Owned), false);
+            },
+            None => preprocessor.source_line(line, Cow::Borrowed(text), comment.map(Cow::Borrowed), true),
+        }
+    }
+    if let Some((first, joined, comments)) = continued {
+        preprocessor.source_line(first, Cow::Owned(joined), Some(comments.join(" ")).filter(|c| !c.is_empty()).map(Cow::Owned), false);
+    }
+    
+    if let Some((name, _, line)) = preprocessor.defining.take() {
+        preprocessor.error(line, None, format!("macro {} is missing .endm", name));
+    }
+    if let Some(repeat) = preprocessor.repeating.take() {
+        preprocessor.error(repeat.line, None, format!("repeat opened on line {} is missing .endr", repeat.line + 1));
+    }
+    for (.., opened) in core::mem::take(&mut preprocessor.conditions) {
+        preprocessor.error(opened, None, format!("conditional opened on line {} is missing .endif", opened + 1));
+    }
+    preprocessor.statements
+}
+
+// A trailing comment goes on the last line its statement produced, unless nothing was produced or it came from an include
+fn attach_comment(lines: &mut [Line], comment: Option<(usize, &str)>, origin: &Arc<String>) {
+    let len = lines.len();
+    if let (Some((start, comment)), Some(line)) = (comment, lines.last_mut()) {
+        if len > start && Arc::ptr_eq(&line.origin, origin) {
+            line.comment = Some(comment.to_owned());
+        }
+    }
+}
+
+// Include parsed on another thread, with where its lines and logs go
+#[cfg(feature = "std")]
+type PendingInclude = (usize, usize, std::thread::JoinHandle<(Vec<Line>, Vec<Log>, HashSet<PathBuf>)>);
+
+#[cfg(feature = "std")]
+fn join_includes(pending: Vec<PendingInclude>, options: Option<&ParseOptions>, origin: &Arc<String>, lines: &mut Vec<Line>, logs: &mut Vec<Log>) {
+    // Parallel includes are spliced back in where they were included, in order. A file that an
+    // earlier include already pulled in is dropped from later ones, just like parsing in order would
+    let mut joined = Vec::new();
+    let mut seen = HashSet::new();
+    for (line_index, log_index, thread) in pending {
+        let (include_lines, include_logs, files) = match thread.join() {
+            Ok(result) => result,
+            Err(_) => (vec![], vec![Log::IOError("include thread panicked".to_owned(), origin.to_string())], HashSet::new()),
+        };
+        let mut canonical = HashMap::new();
+        let mut fresh = |origin: &Arc<String>| *canonical.entry(origin.clone()).or_insert_with(|| {
+            Path::new(origin.as_str()).canonicalize().map_or(true, |path| !seen.contains(&path))
+        });
+        let include_lines: Vec<Line> = include_lines.into_iter().filter(|line| fresh(&line.origin)).collect();
+        let include_logs: Vec<Log> = include_logs.into_iter().filter(|log| match log {
+            Log::Warning(.., origin) | Log::Error(.., origin) => fresh(origin),
+            Log::IOError(..) => true,
+        }).collect();
+        seen.extend(files);
+        joined.push((line_index, log_index, include_lines, include_logs));
+    }
+    if let Some(options) = options {
+        options.included.borrow_mut().extend(seen);
+    }
+    for (line_index, log_index, include_lines, include_logs) in joined.into_iter().rev() {
+        lines.splice(line_index..line_index, include_lines);
+        logs.splice(log_index..log_index, include_logs);
+    }
 }
 
 pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Vec<Log>) {
@@ -112,14 +1088,37 @@ pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Ve
     let mut logs  = Vec::new();
     
     let file_name = match options {
+        #[cfg(feature = "std")]
         Some(opts) => pathbuf_to_string(&opts.origin),
-        None => String::from("[unknown]")
+        _ => String::from("[unknown]")
     };
     
     // Stupid idea but fuck you
-    let origin = Rc::new(file_name);
+    let origin = Arc::new(file_name);
+    
+    let lenient = options.is_some_and(|o| o.lenient_operands);
+    
+    // Includes being parsed on other threads, with where their lines and logs go
+    #[cfg(feature = "std")]
+    let mut pending = Vec::new();
     
-    for (line, source) in source.lines().enumerate() {
+    let no_defines = HashMap::new();
+    let defines = options.map_or(&no_defines, |o| &o.defines);
+    let limits = options.map_or((MAX_LINE_LENGTH, MAX_TOKEN_LENGTH), |o| (o.max_line_length, o.max_token_length));
+    let statements = preprocess(source, defines, limits, &mut logs, &origin);
+    
+    // Local labels starting with '.' belong to the last label without one
+    let mut scope = String::new();
+    let mut numeric = NumericLabels::default();
+    
+    // Which lines belong to a statement is only known once the next one starts
+    let mut comment = None;
+    for Statement {line, column, text, comment: statement_comment} in &statements {
+        attach_comment(&mut lines, comment.take(), &origin);
+        comment = statement_comment.as_deref().map(|c| (lines.len(), c));
+        let (line, column, source) = (*line, *column, &**text);
+        let mut lexer = crate::lexer::new_lexer(source);
+        
         // Pushes new instruction to the lines list
         macro_rules! push_instruction {
             ($name:ident, $ins:expr) => {{
@@ -128,104 +1127,193 @@ pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Ve
                     line,
                     data: LineData::Instruction {
                         $name, params: $ins
-                    }
+                    },
+                    comment: None,
                 });
                 continue;
             }}
         }
-        // Will push an error and then loop back to the start
+        // Will push an error at the last lexed token and then loop back to the start, the code defaults to `Syntax`
         macro_rules! log {
-            ($kind:ident, $msg:expr) => {{
-                logs.push(Log::$kind(line, format!($msg), origin.clone()));
-                continue;
-            }};
-            ($kind:ident, $msg:expr, $($params:expr),+) => {{
-                logs.push(Log::$kind(line, format!($msg, $($params),+), origin.clone()));
+            ($kind:ident, $code:ident, $msg:literal $(, $params:expr)*) => {{
+                logs.push(Log::$kind(line, token_span(&lexer, column), Code::$code, format!($msg $(, $params)*), origin.clone()));
                 continue;
             }};
+            ($kind:ident, $msg:literal $(, $params:expr)*) => {
+                log!($kind, Syntax, $msg $(, $params)*)
+            };
         }
         // Will log the error or warning without looping back to the top
         macro_rules! log_only {
-            ($kind:ident, $msg:expr) => {{
-                logs.push(Log::$kind(line, format!($msg), origin.clone()));
-            }};
-            ($kind:ident, $msg:expr, $($params:expr),+) => {{
-                logs.push(Log::$kind(line, format!($msg, $($params),+), origin.clone()));
+            ($kind:ident, $code:ident, $msg:literal $(, $params:expr)*) => {{
+                logs.push(Log::$kind(line, token_span(&lexer, column), Code::$code, format!($msg $(, $params)*), origin.clone()));
             }};
         }
         
         // Creates a register or logs and error and returns to start
         macro_rules! make_register {
             ($reg:ident) => {{
-                match $reg.parse::<u8>() {
-                    Ok(reg) => {
-                        match Register::from_u8(reg) {
-                            Some(r) => r,
-                            None => log!(Error, "register out of bounds: {}", $reg),
-                        }
-                    },
-                    Err(..) => log!(Error, "register out of bounds: {}", $reg),
+                // `r05` is r5, but `r00` must stay r0
+                let number = match $reg.trim_start_matches('0') {
+                    "" => "0",
+                    number => number,
+                };
+                match number.parse::<u8>().ok().and_then(Register::from_u8) {
+                    Some(r) => r,
+                    None => log!(Error, RegisterOutOfRange, "no such register: r{} (valid range r0-r15)", number),
                 }
             }}
         }
+        // Identifiers like `r` or `rA` in place of a register get their own error
+        macro_rules! malformed_register {
+            ($name:ident) => {
+                log!(Error, MalformedRegister, "malformed register: {}, registers are written r0 to r15", $name)
+            }
+        }
         // Turn immediate token into the integer of type `int`
         macro_rules! make_int {
             ($im:ident, $int:ident) => {{
-                const BITS: usize = std::mem::size_of::<$int>() * 8;
-                let mut chars = $im.chars();
-                let parsed = if let Some('0') = chars.next() {
-                    let mut offset = 2;
-                    match chars.next() {
-                        Some('x') => {
-                            // String truncation logic
-                            if $im.len() > BITS / 4 + 2 {
-                                offset += $im.len() - BITS / 4 - 2;
-                                // Grammar is very important to me
-                                let bits = BITS.to_string();
-                                let indefinite = match bits.as_bytes()[0] {
-                                    b'8' => "an",
-                                    _ => "a",
-                                };
-                                log_only!(Warning, "immediate {} will be truncated to {} {}-bit value", $im, indefinite, bits);
+                const BITS: u32 = core::mem::size_of::<$int>() as u32 * 8;
+                match parse_immediate($im, BITS) {
+                    Ok(i) => i as $int,
+                    Err(ImmError::Truncated(i)) => {
+                        // Grammar is very important to me
+                        let indefinite = if BITS == 8 { "an" } else { "a" };
+                        log_only!(Warning, ImmediateTruncated, "immediate {} will be truncated to {} {}-bit value: 0x{:X}", $im, indefinite, BITS, i as $int);
+                        i as $int
+                    },
+                    Err(ImmError::Invalid(err)) => log!(Error, InvalidImmediate, "could not parse {}: {}", $im, err),
+                }
+            }}
+        }
+        
+        // Next operand after a comma, nothing there is always a trailing comma
+        macro_rules! operand {
+            () => {
+                match lexer.next() {
+                    Some(token) => token,
+                    None => log!(Error, TrailingComma, "trailing ','s are not allowed"),
+                }
+            }
+        }
+        
+        // Turns an ALU immediate into a byte, naming the instruction if it has to be truncated
+        macro_rules! make_byte {
+            ($im:ident, $name:ident) => {{
+                match parse_number($im) {
+                    Ok(value) if value > u8::MAX as i64 => {
+                        log_only!(Warning, ImmediateTruncated, "immediate {} exceeds 8 bits for {}; truncated to 0x{:X}", $im, $name.to_str(), value as u8);
+                        value as u8
+                    },
+                    Ok(value) => value as u8,
+                    Err(err) => log!(Error, "{}", err),
+                }
+            }}
+        }
+        // True if the token begins an expression or names a define
+        macro_rules! is_expression {
+            ($token:ident) => {
+                starts_expression(&$token, &lexer) || matches!($token, Token::Ident(name) if defines.contains_key(name))
+            }
+        }
+        // Evaluates an expression that must be constant into the integer of type `int`
+        macro_rules! make_constant {
+            ($token:expr, $int:ident) => {{
+                let value = match parse_expression(Some($token), &mut lexer) {
+                    Ok(expr) => match expr.evaluate(&|name| defines.get(name).map(|v| *v as i64)) {
+                        Ok(value) => value,
+                        Err(symbol) => log!(Error, UnresolvedSymbol, "{} is not a constant, only 16-bit immediates can reference labels", symbol),
+                    },
+                    Err(err) => log!(Error, "{}", err),
+                };
+                // Negative values are allowed down to the signed minimum
+                let min = -($int::MAX as i64 / 2 + 1);
+                if value < min || value > $int::MAX as i64 {
+                    log_only!(Warning, ImmediateTruncated, "expression value {} will be truncated to {} bits: 0x{:X}", value, core::mem::size_of::<$int>() * 8, value as $int);
+                }
+                value as $int
+            }}
+        }
+        // Turns an expression into a constant if possible or keeps it for codegen
+        macro_rules! make_expression {
+            ($token:expr) => {{
+                match parse_expression(Some($token), &mut lexer) {
+                    Ok(mut expr) => match expr.evaluate(&|name| defines.get(name).map(|v| *v as i64)) {
+                        Ok(value) => {
+                            if value < i16::MIN as i64 || value > u16::MAX as i64 {
+                                log_only!(Warning, ImmediateTruncated, "expression value {} will be truncated to 16 bits: 0x{:X}", value, value as u16);
                             }
-                            $int::from_str_radix(&$im[offset..], 16)
+                            Ok(value)
                         },
-                        
-                        Some('b') => {
-                            // String trunctation logic
-                            if $im.len() > BITS + 2 {
-                                offset += $im.len() - BITS - 2;
-                                // Grammar is very important to me
-                                let bits = format!("{}", BITS);
-                                let indefinite = match bits.as_bytes()[0] {
-                                    b'8' => "an",
-                                    _ => "a",
-                                };
-                                log_only!(Warning, "immediate {} will be truncated to {} {}-bit value", $im, indefinite, bits);
+                        Err(_) => {
+                            let resolved = expr.map_symbols(&mut |name| match name.as_bytes()[0] {
+                                b'0'..=b'9' => numeric.reference(name, line),
+                                _ => Ok(scoped(name, &scope)),
+                            });
+                            match resolved {
+                                Ok(()) => Err(expr),
+                                Err(err) => log!(Error, "{}", err),
                             }
-                            $int::from_str_radix(&$im[offset..], 2)
                         },
-                        
-                        _ => $im.parse::<$int>(),
-                    }
-                } else {
-                    $im.parse::<$int>()
-                };
-                
-                match parsed {
-                    Ok(i) => i,
-                    Err(err) => log!(Error, "could not parse {}: {}", $im, err)
+                    },
+                    Err(err) => log!(Error, "{}", err),
                 }
             }}
         }
         
-        let mut lexer = crate::lexer::new_lexer(source);
+        // Characters the lexer can't match are reported with their text before anything else sees them as a token
+        let mut errors = lexer.clone().spanned().filter(|(token, _)| *token == Token::Error).map(|(_, span)| span);
+        if let Some(mut span) = errors.next() {
+            // Neighbouring characters are one error, `@@` rather than `@` twice
+            for next in errors {
+                if next.start != span.end {
+                    break;
+                }
+                span.end = next.end;
+            }
+            let text = &source[span.clone()];
+            let message = prefix_error(text).or_else(|| non_ascii_error(text)).unwrap_or_else(|| format!("unexpected character(s) '{}'", text));
+            logs.push(Log::Error(line, column.map(|column| span.start + column..span.end + column), Code::Syntax, message, origin.clone()));
+            continue;
+        }
+
+        // A prefix with no digits lexes as `0` and an identifier, or as `0b` the numeric label reference
+        let mut tokens = lexer.clone().spanned().peekable();
+        let mut incomplete = None;
+        while let Some((token, span)) = tokens.next() {
+            match (token, tokens.peek()) {
+                (Token::Immediate("0"), Some((Token::Ident(ident), next))) if next.start == span.end && ident.starts_with(&['x', 'X', 'b', 'B'][..]) => {
+                    incomplete = Some(span.start..next.end);
+                    break;
+                },
+                (Token::NumericLabel("0b"), _) if !numeric.defined.contains_key("0") => {
+                    incomplete = Some(span);
+                    break;
+                },
+                _ => {},
+            }
+        }
+        if let Some(span) = incomplete {
+            let message = prefix_error(&source[span.clone()]).unwrap();
+            logs.push(Log::Error(line, column.map(|column| span.start + column..span.end + column), Code::Syntax, message, origin.clone()));
+            continue;
+        }
+
         let mut first_token = lexer.next();
         
         // Parsing label
         if let Some(Token::Label(l)) = first_token {
-            let data = LineData::Label(l.to_owned());
-            lines.push(Line {origin: origin.clone(), line, data});
+            let data = if NumericLabels::is_numeric(l) {
+                LineData::Label(numeric.define(l))
+            } else {
+                // Reuses the buffer, most lines with a label start a new scope
+                if !l.starts_with('.') {
+                    scope.clear();
+                    scope.push_str(l);
+                }
+                LineData::Label(scoped(l, &scope))
+            };
+            lines.push(Line {origin: origin.clone(), line, data, comment: None});
             first_token = lexer.next();
         }
         
@@ -233,9 +1321,13 @@ pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Ve
         match first_token {
             // Parsing directives
             Some(Token::Directive(dir)) => {
-                match dir {
+                // Directives are case insensitive like instructions
+                match dir.to_ascii_lowercase().as_str() {
                     
+                    #[cfg(not(feature = "std"))]
+                    "include" => log!(Error, "includes need the std feature"),
                     // syntax: .include "hello.h"
+                    #[cfg(feature = "std")]
                     "include" => {
                         match lexer.next() {
                             Some(Token::String(path)) => {
@@ -244,67 +1336,317 @@ pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Ve
                                     Some(options) => options.origin.parent(),
                                     None => Some(Path::new("")),
                                 }.unwrap_or_else(|| Path::new(""));
-                                let file_name = parent.join(path);
+                                let include_paths = options.map(|o| o.include_paths.clone()).unwrap_or_default();
+                                // Then each include path in order, a file found nowhere is reported where it was first looked for
+                                let file_name = core::iter::once(parent.join(path))
+                                    .chain(include_paths.iter().map(|dir| dir.join(path)))
+                                    .find(|file_name| file_name.is_file())
+                                    .unwrap_or_else(|| parent.join(path));
+                                // Reported here so the error points at the `.include` rather than the file that isn't there
+                                if let Err(err) = std::fs::metadata(&file_name) {
+                                    log!(Error, Io, "could not include \"{}\": {}", path, err);
+                                }
+                                // A file still being parsed further up is a cycle, which isn't the same as including a header twice
+                                let canonical = file_name.canonicalize().unwrap_or_else(|_| file_name.clone());
+                                let mut include_stack = options.map(|o| o.include_stack.clone()).unwrap_or_default();
+                                include_stack.extend(options.and_then(|o| o.origin.canonicalize().ok()));
+                                if let Some(start) = include_stack.iter().position(|file| *file == canonical) {
+                                    let cycle: Vec<_> = include_stack[start..].iter().chain(core::iter::once(&canonical)).map(|file| pathbuf_to_string(file)).collect();
+                                    log!(Error, Io, "include cycle: {}", cycle.join(" -> "));
+                                }
+                                let defines = options.map(|o| o.defines.clone()).unwrap_or_default();
+                                let warnings_as_errors = options.is_some_and(|o| o.warnings_as_errors);
+                                let allow = options.map(|o| o.allow.clone()).unwrap_or_default();
+                                let included = options.map(|o| o.included.clone()).unwrap_or_default();
+                                let cache = options.map(|o| o.cache.clone()).unwrap_or_default();
+                                let lenient_operands = lenient;
+                                let (max_line_length, max_token_length) = limits;
+                                
+                                if options.is_some_and(|o| o.parallel_includes) {
+                                    // Claim the file now so repeated includes in this file are still skipped
+                                    if !included.borrow_mut().insert(canonical.clone()) {
+                                        continue;
+                                    }
+                                    // The thread gets its own copy of what has been included, overlap is removed when joining
+                                    let mut seen = included.borrow().clone();
+                                    seen.remove(&canonical);
+                                    let thread = std::thread::spawn(move || {
+                                        let options = ParseOptions {
+                                            origin: file_name,
+                                            include_paths,
+                                            defines,
+                                            warnings_as_errors,
+                                            allow,
+                                            included: Rc::new(RefCell::new(seen)),
+                                            include_stack,
+                                            lenient_operands,
+                                            max_line_length,
+                                            max_token_length,
+                                            ..Default::default()
+                                        };
+                                        let (lines, logs) = parse_file(&options);
+                                        let included = options.included.borrow().clone();
+                                        (lines, logs, included)
+                                    });
+                                    pending.push((lines.len(), logs.len(), thread));
+                                    continue;
+                                }
                                 
                                 let options = ParseOptions {
                                     origin: file_name,
-                                    include_paths: vec![]
+                                    include_paths,
+                                    defines,
+                                    warnings_as_errors,
+                                    allow,
+                                    included,
+                                    include_stack,
+                                    cache,
+                                    lenient_operands,
+                                    max_line_length,
+                                    max_token_length,
+                                    ..Default::default()
                                 };
                                 let (include_lines, include_logs) = parse_file(&options);
                                 lines.extend(include_lines);
                                 logs.extend(include_logs);
-                                // TODO: test paths in include_paths!
                             },
                             Some(token) => log!(Error, "expected a string file path, got: {:?}", token),
                             None => log!(Error, "expected a string file path"),
                         }
                     },
                     
-                    "line" => {
+                    "global" => {
                         match lexer.next() {
-                            Some(Token::Immediate(offset)) => {
-                                match lexer.next() {
-                                    None => {
-                                        let data = LineData::Directive(Directive::Line(make_int!(offset, u16)));
-                                        lines.push(Line {origin: origin.clone(), line, data});
-                                    },
-                                    Some(token) => log!(Error, "unexpected token after line offset: {:?}", token),
-                                }
+                            Some(Token::Ident(name)) => match lexer.next() {
+                                None => {
+                                    let data = LineData::Directive(Directive::Global(name.to_owned()));
+                                    lines.push(Line {origin: origin.clone(), line, data, comment: None});
+                                },
+                                Some(token) => log!(Error, "unexpected token after global symbol: {:?}", token),
+                            },
+                            Some(token) => log!(Error, "expected a label name to export, got: {:?}", token),
+                            None => log!(Error, "expected a label name to export"),
+                        }
+                    },
+                    
+                    "entry" => {
+                        match lexer.next() {
+                            Some(Token::Ident(name)) => match lexer.next() {
+                                None => {
+                                    let data = LineData::Directive(Directive::Entry(name.to_owned()));
+                                    lines.push(Line {origin: origin.clone(), line, data, comment: None});
+                                },
+                                Some(token) => log!(Error, "unexpected token after entry point: {:?}", token),
+                            },
+                            Some(token) => log!(Error, "expected a label name for the entry point, got: {:?}", token),
+                            None => log!(Error, "expected a label name for the entry point"),
+                        }
+                    },
+                    
+                    "line" => {
+                        let offset = match lexer.next() {
+                            Some(token) if is_expression!(token) => make_constant!(token, i64),
+                            Some(Token::Immediate(offset)) => match parse_number(offset) {
+                                Ok(offset) => offset,
+                                Err(err) => log!(Error, InvalidImmediate, "could not parse {}: {}", offset, err),
                             },
                             Some(token) => log!(Error, "expected an immediate for line offset, got: {:?}", token),
                             None => log!(Error, "expected an immediate for line offset"),
+                        };
+                        // Truncating would silently move the code somewhere else entirely
+                        if offset < 0 {
+                            log!(Error, AddressSpace, "line offset {} is outside the 16-bit address space", offset);
+                        } else if offset > u16::MAX as i64 {
+                            log!(Error, AddressSpace, "line offset 0x{:x} is outside the 16-bit address space", offset);
+                        }
+                        match lexer.next() {
+                            None => {
+                                let data = LineData::Directive(Directive::Line(offset as u16));
+                                lines.push(Line {origin: origin.clone(), line, data, comment: None});
+                            },
+                            Some(token) => log!(Error, "unexpected token after line offset: {:?}", token),
                         }
                     },
                     
                     "db" => {
+                        // The byte is kept after an error so the field isn't also reported as empty
+                        macro_rules! db_byte {
+                            ($byte:ident) => {
+                                match parse_immediate($byte, 64) {
+                                    Ok(value) if value > u8::MAX as u64 => {
+                                        log_only!(Error, DbRange, "value {} does not fit in a byte in .db", $byte);
+                                        value as u8
+                                    },
+                                    Err(ImmError::Truncated(value)) => {
+                                        log_only!(Error, DbRange, "value {} does not fit in a byte in .db", $byte);
+                                        value as u8
+                                    },
+                                    _ => make_int!($byte, u8),
+                                }
+                            }
+                        }
                         let mut data_bytes = Vec::new();
                         loop {
                             match lexer.next() {
-                                Some(Token::Immediate(byte)) => data_bytes.push(DataByte::Byte(make_int!(byte, u8))),
+                                // syntax: low(label) high(label)
+                                Some(Token::Ident(select)) if lexer.clone().next() == Some(Token::LeftParen) && ["low", "high"].iter().any(|f| select.eq_ignore_ascii_case(f)) => {
+                                    let select = if select.eq_ignore_ascii_case("low") { ByteSelect::Low } else { ByteSelect::High };
+                                    lexer.next();
+                                    let label = match lexer.next() {
+                                        Some(Token::Ident(l)) => l.to_owned(),
+                                        Some(Token::Directive(l)) => format!("{}.{}", scope, l),
+                                        Some(Token::NumericLabel(l)) => match numeric.reference(l, line) {
+                                            Ok(label) => label,
+                                            Err(err) => log!(Error, "{}", err),
+                                        },
+                                        Some(token) => log!(Error, "expected a label, got: {:?}", token),
+                                        None => log!(Error, "expected a label"),
+                                    };
+                                    match lexer.next() {
+                                        Some(Token::RightParen) => data_bytes.push(DataByte::LabelByte(label, select)),
+                                        Some(token) => log!(Error, "expected ')' after label, got: {:?}", token),
+                                        None => log!(Error, "expected ')' after label"),
+                                    }
+                                },
+                                // syntax: .db 0xFF * 16, a literal followed by `*` is repeated, multiplying needs parentheses
+                                Some(Token::Immediate(byte)) if lexer.clone().next() == Some(Token::Star) => {
+                                    lexer.next();
+                                    let count = match lexer.next() {
+                                        Some(Token::Immediate(count)) => match parse_number(count) {
+                                            Ok(count) => count,
+                                            Err(err) => log!(Error, "{}", err),
+                                        },
+                                        Some(token) => log!(Error, "expected a repeat count, got: {:?}", token),
+                                        None => log!(Error, "expected a repeat count"),
+                                    };
+                                    let count = match count {
+                                        0 => {
+                                            log_only!(Error, Syntax, "repeat count must be at least 1");
+                                            1
+                                        },
+                                        count if count > 0x10000 => {
+                                            log_only!(Error, AddressSpace, "repeat count {} exceeds the 16-bit address space", count);
+                                            1
+                                        },
+                                        count => count as usize,
+                                    };
+                                    let value = db_byte!(byte);
+                                    data_bytes.extend(core::iter::repeat_n(DataByte::Byte(value), count));
+                                },
+                                // Constant expressions are a single byte, anything with a label is a full address
+                                Some(token) if is_expression!(token) => match make_expression!(token) {
+                                    Ok(value) => {
+                                        let value = value as u16;
+                                        // Negative values down to -128 wrap into the byte
+                                        if value > u8::MAX as u16 && value < 0xFF80 {
+                                            log_only!(Error, DbRange, "value {} does not fit in a byte in .db", value);
+                                        }
+                                        data_bytes.push(DataByte::Byte(value as u8))
+                                    },
+                                    Err(expr) => data_bytes.push(DataByte::Expression(expr)),
+                                },
+                                Some(Token::Immediate(byte)) => data_bytes.push(DataByte::Byte(db_byte!(byte))),
                                 Some(Token::Ident(l)) => data_bytes.push(DataByte::Label(l.to_owned())),
+                                Some(Token::Directive(l)) => data_bytes.push(DataByte::Label(format!("{}.{}", scope, l))),
+                                Some(Token::NumericLabel(l)) => match numeric.reference(l, line) {
+                                    Ok(label) => data_bytes.push(DataByte::Label(label)),
+                                    Err(err) => log!(Error, "{}", err),
+                                },
                                 Some(Token::String(s)) => data_bytes.extend(s.as_bytes().iter().map(|b| DataByte::Byte(*b))),
                                 Some(token) => log!(Error, "unexpected token in db field: {:?}", token),
                                 None => {
                                     if data_bytes.is_empty() {
-                                        log!(Warning, "empty db field");
+                                        log_only!(Warning, EmptyDb, "empty db field");
                                     }
-                                    lines.push(Line {origin: origin.clone(), line, data: LineData::Directive(Directive::DB(data_bytes))});
+                                    lines.push(Line {origin: origin.clone(), line, data: LineData::Directive(Directive::DB(data_bytes)), comment: None});
                                     break;
                                 }
                             }
                         }
                     },
                     
-                    _ => log!(Error, "unknown directive: {}", dir)
+                    _ => match closest_name(&dir.to_ascii_lowercase(), DIRECTIVES.iter().copied()) {
+                        Some(close) => log!(Error, UnknownDirective, "unknown directive: {}, did you mean `.{}`?", dir, close),
+                        None => log!(Error, UnknownDirective, "unknown directive: {}", dir),
+                    }
+                }
+            },
+            
+            // Parsing pseudo-instructions
+            Some(Token::Ident(ins)) if ins.eq_ignore_ascii_case("li") => {
+                let reg = match lexer.next() {
+                    Some(Token::Register(r)) => make_register!(r),
+                    Some(token) => log!(Error, "LI expects one register and an immediate, got: {:?}", token),
+                    None => log!(Error, "LI expects one register and an immediate"),
+                };
+                if reg.as_u8() == 15 {
+                    log!(Error, RegisterOutOfRange, "LI loads a register pair, r15 has no register after it");
+                }
+                match lexer.next() {
+                    Some(Token::Comma) => {},
+                    Some(token) => log!(Error, "expected ',' after register, got: {:?}", token),
+                    None => log!(Error, "LI expects one register and an immediate"),
+                }
+                let i = match operand!() {
+                    token if is_expression!(token) => make_constant!(token, u16),
+                    Token::Immediate(i) => make_int!(i, u16),
+                    token => log!(Error, "expected an immediate, got: {:?}", token),
+                };
+                match lexer.next() {
+                    None => {
+                        let data = LineData::Pseudo(Pseudo::LoadImmediate(reg, i));
+                        lines.push(Line {origin: origin.clone(), line, data, comment: None});
+                    },
+                    Some(token) => log!(Error, "unexpected token after immediate: {:?}", token),
+                }
+            },
+            
+            // Names a fixed address, memory mapped registers and the like
+            Some(Token::Ident(label)) if lexer.clone().next() == Some(Token::Equals) => {
+                lexer.next();
+                let address = match lexer.next() {
+                    Some(token) if is_expression!(token) || matches!(token, Token::Immediate(_) | Token::Ident(_)) => match make_expression!(token) {
+                        Ok(address) => address as u16,
+                        Err(_) => log!(Error, UnresolvedSymbol, "address of {} must be a constant", label),
+                    },
+                    Some(token) => log!(Error, "expected an address, got: {:?}", token),
+                    None => log!(Error, "expected an address after `{} =`", label),
+                };
+                match lexer.next() {
+                    None => lines.push(Line {origin: origin.clone(), line, data: LineData::AbsoluteLabel(label.to_owned(), address), comment: None}),
+                    Some(token) => log!(Error, "unexpected token after address: {:?}", token),
                 }
             },
             
             // Parsing instructions
             Some(Token::Ident(ins)) => {
-                let name: Instruction = match Instruction::from_str(&ins.to_uppercase()) {
+                let name: Instruction = match Instruction::from_name(ins) {
                     Some(ins) => ins,
-                    None => log!(Error, "unknown instruction: {}", ins),
+                    // `addr1` lexes as one identifier when the space is left out
+                    None => match (Instruction::split_register(ins), Instruction::closest(ins)) {
+                        (Some((name, reg)), _) => log!(Error, UnknownInstruction, "unknown instruction: {}, missing a space in `{} {}`?", ins, name.to_str(), reg),
+                        (None, Some(close)) => log!(Error, UnknownInstruction, "unknown instruction: {}, did you mean `{}`?", ins, close.to_str()),
+                        (None, None) => log!(Error, UnknownInstruction, "unknown instruction: {}", ins),
+                    },
+                };
+                
+                // Special register instructions may name their register first, `lsp sp, r0, r1`. Anywhere
+                // else the names are ordinary identifiers, so labels called `sp` still work
+                let mut operands = lexer.clone();
+                let alias = match (name.special_register(), operands.next(), operands.next()) {
+                    (Some(expected), Some(Token::Ident(special)), Some(Token::Comma)) if SPECIAL_REGISTERS.contains(&special) => Some((expected, special)),
+                    _ => None,
                 };
+                if let Some((expected, special)) = alias {
+                    lexer.next();
+                    lexer.next();
+                    if expected != special {
+                        log!(Error, "{} accesses {}, not {}", name.to_str(), expected, special);
+                    }
+                    if lexer.clone().next().is_none() {
+                        log!(Error, TrailingComma, "trailing ','s are not allowed");
+                    }
+                }
                 
                 let asm_info = name.assemble_info();
                 match asm_info.1 {
@@ -316,19 +1658,39 @@ pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Ve
                     OperandMode::OneRegister => {
                         let reg = match lexer.next() {
                             Some(Token::Register(r)) => make_register!(r),
-                            Some(token) => log!(Error, "{} expectes one register, got: {:?}", name.to_str(), token),
+                            Some(Token::Ident(r)) if looks_like_register(r) => malformed_register!(r),
+                            Some(token) => log!(Error, "{} expects one register, got: {:?}", name.to_str(), token),
                             None => log!(Error, "{} requires one register", name.to_str()),
                         };
                         match lexer.next() {
                             None => push_instruction!(name, Parameters::OneRegister(reg)),
+                            Some(Token::Minus) if matches!(name, Instruction::PUSH | Instruction::POP) => {},
+                            Some(Token::Minus) => log!(Error, "register ranges only work with PUSH and POP, {} takes one register", name.to_str()),
                             Some(token) => log!(Error, "unexpected token after register: {:?}", token),
                         }
+                        let last = match lexer.next() {
+                            Some(Token::Register(r)) => make_register!(r),
+                            Some(Token::Ident(r)) if looks_like_register(r) => malformed_register!(r),
+                            Some(token) => log!(Error, "expected the last register of the range, got: {:?}", token),
+                            None => log!(Error, "expected the last register of the range"),
+                        };
+                        if last.as_u8() < reg.as_u8() {
+                            log!(Error, "register range r{0}-r{1} runs backwards, write it as r{1}-r{0}", reg.as_u8(), last.as_u8());
+                        }
+                        match lexer.next() {
+                            None => {
+                                let data = LineData::Pseudo(Pseudo::RegisterRange(name, reg, last));
+                                lines.push(Line {origin: origin.clone(), line, data, comment: None});
+                            },
+                            Some(token) => log!(Error, "unexpected token after register range: {:?}", token),
+                        }
                     },
                     
                     OperandMode::OneOrTwoRegisters => {
                         let reg1 = match lexer.next() {
                             Some(Token::Register(r)) => make_register!(r),
-                            Some(token) => log!(Error, "{} expects at leat one register, got: {:?}", name.to_str(), token),
+                            Some(Token::Ident(r)) if looks_like_register(r) => malformed_register!(r),
+                            Some(token) => log!(Error, "{} expects at least one register, got: {:?}", name.to_str(), token),
                             None => log!(Error, "{} expects at least one register", name.to_str()),
                         };
                         match lexer.next() {
@@ -336,11 +1698,15 @@ pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Ve
                             Some(Token::Comma) => {},
                             Some(token) => log!(Error, "expected ',' after first register, got: {:?}", token),
                         }
-                        let reg2 = match lexer.next() {
-                            Some(Token::Register(r)) => make_register!(r),
-                            Some(token) => log!(Error, "expected a register, got: {:?}", token),
-                            None => log!(Error, "trailing ','s are not allowed"),
+                        let reg2 = match operand!() {
+                            Token::Register(r) => make_register!(r),
+                            Token::Ident(r) if looks_like_register(r) => malformed_register!(r),
+                            token => log!(Error, "expected a register, got: {:?}", token),
                         };
+                        // The one register form already uses its register twice
+                        if reg1.as_u8() == reg2.as_u8() {
+                            log_only!(Warning, RedundantOperand, "`{0} r{1}, r{1}` is the same as `{0} r{1}`", name.to_str(), reg1.as_u8());
+                        }
                         match lexer.next() {
                             None => push_instruction!(name, Parameters::TwoRegisters(reg1, reg2)),
                             Some(token) => log!(Error, "unexpected token after second register: {:?}", token),
@@ -350,6 +1716,7 @@ pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Ve
                     OperandMode::OneRegisterAndImmediate => {
                         let reg = match lexer.next() {
                             Some(Token::Register(r)) => make_register!(r),
+                            Some(Token::Ident(r)) if looks_like_register(r) => malformed_register!(r),
                             Some(token) => log!(Error, "{} expects one register and an immediate, got: {:?}", name.to_str(), token),
                             None => log!(Error, "{} expects one register and an immediate", name.to_str()),
                         };
@@ -358,10 +1725,10 @@ pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Ve
                             Some(token) => log!(Error, "expected ',' after register, got: {:?}", token),
                             None => log!(Error, "{} expects one register and an immediate", name.to_str()),
                         }
-                        let i = match lexer.next() {
-                            Some(Token::Immediate(i)) => make_int!(i, u8),
-                            Some(token) => log!(Error, "expected a regsiter, got: {:?}", token),
-                            None => log!(Error, "trailing ','s are not allowed"),
+                        let i = match operand!() {
+                            token if is_expression!(token) => make_constant!(token, u8),
+                            Token::Immediate(i) => make_int!(i, u8),
+                            token => log!(Error, "expected an immediate, got: {:?}", token),
                         };
                         match lexer.next() {
                             None => push_instruction!(name, Parameters::OneRegisterImmediate(reg, i)),
@@ -369,9 +1736,36 @@ pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Ve
                         }
                     },
                     
+                    OperandMode::OneRegisterAndLongImmediate => {
+                        let reg = match lexer.next() {
+                            Some(Token::Register(r)) => make_register!(r),
+                            Some(Token::Ident(r)) if looks_like_register(r) => malformed_register!(r),
+                            Some(token) => log!(Error, "{} expects one register and an address, got: {:?}", name.to_str(), token),
+                            None => log!(Error, "{} expects one register and an address", name.to_str()),
+                        };
+                        match lexer.next() {
+                            Some(Token::Comma) => {},
+                            Some(token) => log!(Error, "expected ',' after register, got: {:?}", token),
+                            None => log!(Error, "{} expects one register and an address", name.to_str()),
+                        }
+                        let params = match operand!() {
+                            // Lone immediates and labels are parsed as expressions too
+                            token if is_expression!(token) || matches!(token, Token::Immediate(_) | Token::Ident(_) | Token::Directive(_) | Token::NumericLabel(_)) => match make_expression!(token) {
+                                Ok(i) => Parameters::OneRegisterLongImmediate(reg, i as u16),
+                                Err(expr) => Parameters::OneRegisterExpression(reg, expr),
+                            },
+                            token => log!(Error, "expected an address, got: {:?}", token),
+                        };
+                        match lexer.next() {
+                            None => push_instruction!(name, params),
+                            Some(token) => log!(Error, "unexpected token after address: {:?}", token),
+                        }
+                    },
+                    
                     OperandMode::TwoRegisters => {
                         let reg1 = match lexer.next() {
                             Some(Token::Register(r)) => make_register!(r),
+                            Some(Token::Ident(r)) if looks_like_register(r) => malformed_register!(r),
                             Some(token) => log!(Error, "{} expects two registers, got: {:?}", name.to_str(), token),
                             None => log!(Error, "{} expects two registers", name.to_str()),
                         };
@@ -380,10 +1774,10 @@ pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Ve
                             Some(token) => log!(Error, "expected ',' after first register, got: {:?}", token),
                             None => log!(Error, "{} expects two registers", name.to_str()),
                         }
-                        let reg2 = match lexer.next() {
-                            Some(Token::Register(r)) => make_register!(r),
-                            Some(token) => log!(Error, "expected a regsiter, got: {:?}", token),
-                            None => log!(Error, "{} expects two registers", name.to_str()),
+                        let reg2 = match operand!() {
+                            Token::Register(r) => make_register!(r),
+                            Token::Ident(r) if looks_like_register(r) => malformed_register!(r),
+                            token => log!(Error, "expected a register, got: {:?}", token),
                         };
                         match lexer.next() {
                             None => push_instruction!(name, Parameters::TwoRegisters(reg1, reg2)),
@@ -394,6 +1788,33 @@ pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Ve
                     OperandMode::TwoRegistersOrImmediate => {
                         let reg1 = match lexer.next() {
                             Some(Token::Register(r)) => make_register!(r),
+                            Some(Token::Ident(r)) if looks_like_register(r) => malformed_register!(r),
+                            // `add 5, r1` is only accepted in lenient mode, and only where the operands commute
+                            Some(token) if matches!(token, Token::Immediate(_)) || is_expression!(token) => {
+                                if !lenient || !name.is_commutative() {
+                                    log!(Error, OperandOrder, "{} takes its register first, the immediate goes last", name.to_str());
+                                }
+                                let i = match token {
+                                    token if is_expression!(token) => make_constant!(token, u8),
+                                    Token::Immediate(i) => make_byte!(i, name),
+                                    token => log!(Error, "expected an immediate, got: {:?}", token),
+                                };
+                                match lexer.next() {
+                                    Some(Token::Comma) => {},
+                                    Some(token) => log!(Error, "expected ',' after immediate, got: {:?}", token),
+                                    None => log!(Error, "{} expects a register after the immediate", name.to_str()),
+                                }
+                                let reg = match operand!() {
+                                    Token::Register(r) => make_register!(r),
+                                    Token::Ident(r) if looks_like_register(r) => malformed_register!(r),
+                                    token => log!(Error, "expected a register, got: {:?}", token),
+                                };
+                                log_only!(Warning, OperandOrder, "operands of {0} reordered to `{0} r{1}, {2}`", name.to_str(), reg.as_u8(), i);
+                                match lexer.next() {
+                                    None => push_instruction!(name, Parameters::OneRegisterImmediate(reg, i)),
+                                    Some(token) => log!(Error, "unexpected token after register: {:?}", token),
+                                }
+                            },
                             Some(token) => log!(Error, "{} expects at least two parameters, got: {:?}", name.to_str(), token),
                             None => log!(Error, "{} expects at least two parameters", name.to_str()),
                         };
@@ -402,27 +1823,73 @@ pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Ve
                             Some(token) => log!(Error, "expected ',' after first register, got: {:?}", token),
                             None => log!(Error, "{} expects two registers", name.to_str()),
                         }
-                        let reg2 = match lexer.next() {
-                            Some(Token::Register(r)) => make_register!(r),
-                            Some(Token::Immediate(i)) => match lexer.next() {
-                                None => push_instruction!(name, Parameters::OneRegisterImmediate(reg1, make_int!(i, u8))),
-                                Some(token) => log!(Error, "unexpected token after immediate: {:?}", token),
+                        let reg2 = match operand!() {
+                            Token::Register(r) => make_register!(r),
+                            token if is_expression!(token) => {
+                                let i = make_constant!(token, u8);
+                                match lexer.next() {
+                                    None => push_instruction!(name, Parameters::OneRegisterImmediate(reg1, i)),
+                                    Some(token) => log!(Error, "unexpected token after immediate: {:?}", token),
+                                }
+                            },
+                            Token::Immediate(i) => {
+                                let i = make_byte!(i, name);
+                                match lexer.next() {
+                                    None => push_instruction!(name, Parameters::OneRegisterImmediate(reg1, i)),
+                                    Some(token) => log!(Error, "unexpected token after immediate: {:?}", token),
+                                }
                             },
-                            Some(token) => log!(Error, "expected a regsiter or an immediate, got: {:?}", token),
-                            None => log!(Error, "{} expects as least two parameters", name.to_str()),
+                            Token::Ident(r) if looks_like_register(r) => malformed_register!(r),
+                            token => log!(Error, "expected a register or an immediate, got: {:?}", token),
                         };
                         match lexer.next() {
                             None => push_instruction!(name, Parameters::TwoRegisters(reg1, reg2)),
                             Some(Token::Comma) => {},
                             Some(token) => log!(Error, "expected ',' after second register, got: {:?}", token),
                         }
-                        let i = match lexer.next() {
-                            Some(Token::Immediate(i)) => make_int!(i, u8),
-                            Some(token) => log!(Error, "expected an immediate, got: {:?}", token),
+                        let i = match operand!() {
+                            token if is_expression!(token) => make_constant!(token, u8),
+                            Token::Immediate(i) => make_byte!(i, name),
+                            token => log!(Error, "expected an immediate, got: {:?}", token),
+                        };
+                        if reg1.as_u8() == reg2.as_u8() {
+                            log_only!(Warning, RedundantOperand, "`{0} r{1}, r{1}, {2}` is the same as `{0} r{1}, {2}`", name.to_str(), reg1.as_u8(), i);
+                        }
+                        match lexer.next() {
+                            None => push_instruction!(name, Parameters::TwoRegistersImmediate(reg1, reg2, i)),
+                            Some(token) => log!(Error, "unexpected token after immediate: {:?}", token),
+                        }
+                    },
+                    
+                    OperandMode::TwoRegistersAndLongImmediate => {
+                        let reg1 = match lexer.next() {
+                            Some(Token::Register(r)) => make_register!(r),
+                            Some(Token::Ident(r)) if looks_like_register(r) => malformed_register!(r),
+                            Some(token) => log!(Error, "{} expects two registers and an immediate, got: {:?}", name.to_str(), token),
+                            None => log!(Error, "{} expects two registers and an immediate", name.to_str()),
+                        };
+                        match lexer.next() {
+                            Some(Token::Comma) => {},
+                            Some(token) => log!(Error, "expected ',' after first register, got: {:?}", token),
+                            None => log!(Error, "{} expects two registers and an immediate", name.to_str()),
+                        }
+                        let reg2 = match operand!() {
+                            Token::Register(r) => make_register!(r),
+                            Token::Ident(r) if looks_like_register(r) => malformed_register!(r),
+                            token => log!(Error, "expected a register, got: {:?}", token),
+                        };
+                        match lexer.next() {
+                            Some(Token::Comma) => {},
+                            Some(token) => log!(Error, "expected ',' after second register, got: {:?}", token),
                             None => log!(Error, "{} expects two registers and an immediate", name.to_str()),
+                        }
+                        let i = match operand!() {
+                            token if is_expression!(token) => make_constant!(token, u16),
+                            Token::Immediate(i) => make_int!(i, u16),
+                            token => log!(Error, "expected an immediate, got: {:?}", token),
                         };
                         match lexer.next() {
-                            None => push_instruction!(name, Parameters::TwoRegistersImmedaite(reg1, reg2, i)),
+                            None => push_instruction!(name, Parameters::TwoRegistersLongImmediate(reg1, reg2, i)),
                             Some(token) => log!(Error, "unexpected token after immediate: {:?}", token),
                         }
                     },
@@ -430,6 +1897,25 @@ pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Ve
                     OperandMode::TwoRegistersOrLongImmediate => {
                         let reg1 = match lexer.next() {
                             Some(Token::Register(r)) => make_register!(r),
+                            Some(token) if is_expression!(token) => {
+                                let params = match make_expression!(token) {
+                                    Ok(i) => {
+                                        if name.is_relative() && !(i16::MIN as i64..=i16::MAX as i64).contains(&i) {
+                                            log!(Error, AddressSpace, "relative offset {} out of range ({}..{})", i, i16::MIN, i16::MAX);
+                                        }
+                                        Parameters::LongImmediate(i as u16)
+                                    },
+                                    Err(expr) => Parameters::Expression(expr),
+                                };
+                                match lexer.next() {
+                                    None => push_instruction!(name, params),
+                                    Some(token) => log!(Error, "unexpected token after expression: {:?}", token),
+                                }
+                            },
+                            // Relative offsets are signed, but hex and binary give the encoded bits, `0xFFFE` is -2
+                            Some(Token::Immediate(i)) if name.is_relative() && i.bytes().all(|b| b.is_ascii_digit()) && parse_number(i).is_ok_and(|i| i > i16::MAX as i64) => {
+                                log!(Error, AddressSpace, "relative offset {} out of range ({}..{})", i, i16::MIN, i16::MAX)
+                            },
                             Some(Token::Immediate(i)) => match lexer.next() {
                                 None => push_instruction!(name, Parameters::LongImmediate(make_int!(i, u16))),
                                 Some(token) => log!(Error, "unexpected token after immediate: {:?}", token)
@@ -438,6 +1924,20 @@ pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Ve
                                 None => push_instruction!(name, Parameters::Label(l.to_owned())),
                                 Some(token) => log!(Error, "unexpected token after label: {:?}", token)
                             },
+                            Some(Token::Directive(l)) => match lexer.next() {
+                                None => push_instruction!(name, Parameters::Label(format!("{}.{}", scope, l))),
+                                Some(token) => log!(Error, "unexpected token after label: {:?}", token)
+                            },
+                            Some(Token::NumericLabel(l)) => {
+                                let label = match numeric.reference(l, line) {
+                                    Ok(label) => label,
+                                    Err(err) => log!(Error, "{}", err),
+                                };
+                                match lexer.next() {
+                                    None => push_instruction!(name, Parameters::Label(label)),
+                                    Some(token) => log!(Error, "unexpected token after label: {:?}", token)
+                                }
+                            },
                             Some(token) => log!(Error, "{} expects two registers, got: {:?}", name.to_str(), token),
                             None => log!(Error, "{} expects two registers", name.to_str()),
                         };
@@ -446,10 +1946,10 @@ pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Ve
                             Some(token) => log!(Error, "expected ',' after first register, got: {:?}", token),
                             None => log!(Error, "{} expects two registers", name.to_str()),
                         }
-                        let reg2 = match lexer.next() {
-                            Some(Token::Register(r)) => make_register!(r),
-                            Some(token) => log!(Error, "expected a regsiter, got: {:?}", token),
-                            None => log!(Error, "{} expects two registers", name.to_str()),
+                        let reg2 = match operand!() {
+                            Token::Register(r) => make_register!(r),
+                            Token::Ident(r) if looks_like_register(r) => malformed_register!(r),
+                            token => log!(Error, "expected a register, got: {:?}", token),
                         };
                         match lexer.next() {
                             None => push_instruction!(name, Parameters::TwoRegisters(reg1, reg2)),
@@ -468,6 +1968,562 @@ pub fn parse_raw(source: &str, options: Option<&ParseOptions>) -> (Vec<Line>, Ve
             None => continue,
         }
     }
+    attach_comment(&mut lines, comment, &origin);
     
+    #[cfg(feature = "std")]
+    join_includes(pending, options, &origin, &mut lines, &mut logs);
+    
+    for (label, index, line) in numeric.forward {
+        if index >= numeric.defined.get(&label).copied().unwrap_or(0) {
+            logs.push(Log::Error(line, None, Code::UnresolvedSymbol, format!("numeric label {} has no following definition", label), origin.clone()));
+        }
+    }
+    
+    if let Some(options) = options {
+        logs.retain(|log| log.is_error() || !options.allow.contains(&log.code()));
+        if options.warnings_as_errors {
+            logs = logs.into_iter().map(Log::into_error).collect();
+        }
+    }
+    
+    let (lines, pseudo_logs) = expand_pseudo(lines);
+    logs.extend(pseudo_logs);
     (lines, logs)
 }
+
+/// Replaces every pseudo-instruction with the real instructions it stands for.
+/// Lines built by hand can hold pseudo-instructions the parser would have rejected, those are
+/// reported and left out
+pub fn expand_pseudo(lines: Vec<Line>) -> (Vec<Line>, Vec<Log>) {
+    let mut expanded = Vec::with_capacity(lines.len());
+    let mut logs = Vec::new();
+    for line in lines {
+        let pseudo = match &line.data {
+            LineData::Pseudo(pseudo) => pseudo.clone(),
+            _ => {
+                expanded.push(line);
+                continue;
+            }
+        };
+        // The comment stays with the first instruction, it was written about all of them
+        let mut comment = line.comment.clone();
+        let mut push_instruction = |name, params| expanded.push(Line {
+            origin: line.origin.clone(),
+            line: line.line,
+            data: LineData::Instruction {name, params},
+            comment: comment.take(),
+        });
+        
+        let mut error = |code, message| logs.push(Log::Error(line.line, None, code, message, line.origin.clone()));
+        
+        match pseudo {
+            Pseudo::LoadImmediate(reg, i) => {
+                let high = match Register::from_u8(reg.as_u8() + 1) {
+                    Some(high) => high,
+                    None => {
+                        error(Code::RegisterOutOfRange, format!("LI loads a register pair, r{} has no register after it", reg.as_u8()));
+                        continue;
+                    },
+                };
+                push_instruction(Instruction::SET, Parameters::OneRegisterImmediate(reg, (i & 0xFF) as u8));
+                // Clearing is one byte shorter than setting to zero
+                match (i >> 8) as u8 {
+                    0 => push_instruction(Instruction::CLR, Parameters::OneRegister(high)),
+                    hi => push_instruction(Instruction::SET, Parameters::OneRegisterImmediate(high, hi)),
+                }
+            },
+            Pseudo::RegisterRange(name, first, last) => {
+                if !matches!(name, Instruction::PUSH | Instruction::POP) {
+                    error(Code::Syntax, format!("register ranges only work with PUSH and POP, {} takes one register", name.to_str()));
+                    continue;
+                }
+                if last.as_u8() < first.as_u8() {
+                    error(Code::Syntax, format!("register range r{0}-r{1} runs backwards, write it as r{1}-r{0}", first.as_u8(), last.as_u8()));
+                    continue;
+                }
+                // `pop` goes from the top down so that it undoes a `push` of the same range
+                let mut registers: Vec<u8> = (first.as_u8()..=last.as_u8()).collect();
+                if matches!(name, Instruction::POP) {
+                    registers.reverse();
+                }
+                // Every register between two valid ones is valid too
+                for r in registers.into_iter().filter_map(Register::from_u8) {
+                    push_instruction(name, Parameters::OneRegister(r));
+                }
+            },
+        }
+    }
+    (expanded, logs)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::lexer::{Token, new_lexer};
+    use crate::parser::{Code, DataByte, Directive, ImmError, Line, LineData, Log, Parameters, ParseOptions, parse_immediate, parse_raw};
+    use std::path::PathBuf;
+    
+    #[test]
+    fn register_column() {
+        let (_, logs) = parse_raw("    set r99, 1", None);
+        assert_eq!(logs.len(), 1);
+        match &logs[0] {
+            Log::Error(line, Some(span), ..) => {
+                assert_eq!(*line, 0);
+                assert_eq!(*span, 8..11);
+            },
+            log => panic!("unexpected log: {:?}", log),
+        }
+        assert!(logs[0].message().starts_with("[unknown]:1:9: "));
+    }
+    
+    #[test]
+    fn suggestions() {
+        let (_, logs) = parse_raw("ad r1, r2\n.lin 5", None);
+        assert!(logs[0].message().ends_with("unknown instruction: ad, did you mean `ADD`?"));
+        assert!(logs[1].message().ends_with("unknown directive: lin, did you mean `.line`?"));
+    }
+    
+    #[test]
+    fn register_ranges() {
+        // Ranges are expanded into one instruction per register, on the line they were written on
+        let (lines, logs) = parse_raw("push r1-r4\npop r1 - r2 // restore\npush r7-r7", None);
+        assert!(logs.is_empty(), "{:?}", logs);
+        let text: Vec<_> = lines.iter().map(|l| (l.data.to_string(), l.line)).collect();
+        assert_eq!(text, [
+            ("push r1".to_owned(), 0), ("push r2".to_owned(), 0), ("push r3".to_owned(), 0), ("push r4".to_owned(), 0),
+            ("pop r2".to_owned(), 1), ("pop r1".to_owned(), 1), ("push r7".to_owned(), 2),
+        ]);
+        assert!(lines[4].comment.is_some());
+        assert!(lines[5].comment.is_none());
+        
+        let (lines, logs) = parse_raw("push r4-r1\npush r1-r16\npush r1-\npush r1-5\nadd r1-r2\nclr r0-r1\ninc r1-r2", None);
+        assert!(lines.is_empty());
+        assert_eq!(logs.len(), 7);
+        assert!(logs[0].message().ends_with("register range r4-r1 runs backwards, write it as r1-r4"));
+        assert!(logs[1].message().ends_with("no such register: r16 (valid range r0-r15)"));
+        assert!(logs[2].message().ends_with("expected the last register of the range"));
+        assert!(logs[3].message().contains("expected the last register of the range, got: Immediate"));
+        assert!(logs[4].message().contains("expected ',' after first register, got: Minus"));
+        assert!(logs[5].message().ends_with("6:7: register ranges only work with PUSH and POP, CLR takes one register"));
+        assert!(logs[6].message().contains("got: Minus"));
+    }
+    
+    #[test]
+    fn malformed_pseudo() {
+        use crate::codegen::Register;
+        use crate::instruction::Instruction;
+        use crate::parser::{Pseudo, expand_pseudo};
+        use std::sync::Arc;
+        
+        // Library callers can build pseudo-instructions the parser never would
+        let r = |n| Register::from_u8(n).unwrap();
+        let line = |line, pseudo| Line {origin: Arc::new(String::from("hand.asm")), line, data: LineData::Pseudo(pseudo), comment: None};
+        let (lines, logs) = expand_pseudo(vec![
+            line(0, Pseudo::LoadImmediate(r(15), 0x1234)),
+            line(1, Pseudo::RegisterRange(Instruction::CLR, r(0), r(1))),
+            line(2, Pseudo::RegisterRange(Instruction::PUSH, r(3), r(1))),
+            line(3, Pseudo::LoadImmediate(r(14), 0x1234)),
+        ]);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(logs.len(), 3);
+        assert_eq!(logs[0].message(), "hand.asm:1: LI loads a register pair, r15 has no register after it");
+        assert_eq!(logs[1].message(), "hand.asm:2: register ranges only work with PUSH and POP, CLR takes one register");
+        assert_eq!(logs[2].message(), "hand.asm:3: register range r3-r1 runs backwards, write it as r1-r3");
+    }
+    
+    #[test]
+    fn comma_spacing() {
+        // Commas are their own token so spaces around them are optional
+        let (tight, logs) = parse_raw("add r1,r2", None);
+        assert!(logs.is_empty());
+        let (loose, logs) = parse_raw("add r1 , r2", None);
+        assert!(logs.is_empty());
+        assert_eq!(format!("{:?}", tight[0].data), format!("{:?}", loose[0].data));
+        
+        // The space after the mnemonic is what separates it from the register
+        let (_, logs) = parse_raw("addr1, r2\nstrsp, r0\naddress", None);
+        assert_eq!(logs.len(), 3);
+        assert!(logs[0].message().ends_with("unknown instruction: addr1, missing a space in `ADD r1`?"));
+        assert!(logs[1].message().ends_with("unknown instruction: strsp, missing a space in `STR sp`?"));
+        assert_eq!(logs[2].code(), Code::UnknownInstruction);
+        assert!(!logs[2].message().contains("missing a space"));
+    }
+    
+    #[test]
+    fn statement_columns() {
+        let (lines, logs) = parse_raw("nop; set r99, 1; .db \"a;b\"", None);
+        assert_eq!(lines.len(), 2);
+        match &logs[0] {
+            Log::Error(0, Some(span), ..) => assert_eq!(*span, 9..12),
+            log => panic!("unexpected log: {:?}", log),
+        }
+    }
+    
+    #[test]
+    fn macro_errors() {
+        let (_, logs) = parse_raw(".macro twice a\ntwice a\n.endm\ntwice r1", None);
+        assert!(logs[0].message().contains("exceeds the expansion depth limit"));
+        
+        let (_, logs) = parse_raw(".macro one a\nclr a\n.endm\none r1, r2\n.endm\n.macro open", None);
+        assert!(logs[0].message().contains("macro one expects 1 arguments, got 2"));
+        assert!(logs[1].message().contains(".endm without a matching .macro"));
+        assert!(logs[2].message().contains("macro open is missing .endm"));
+    }
+    
+    #[test]
+    fn conditionals() {
+        let source = "
+        .ifdef DEBUG
+            nop
+        .else
+            clr r0
+            .if DEBUG_LEVEL - 1
+            .endif
+        .endif
+        .if (DEBUG_LEVEL + 1) * 2 - 4
+            clr r1
+        .endif
+        .ifndef DEBUG
+            clr r2
+        .endif";
+        let options = |defines: &[(&str, u16)]| ParseOptions {
+            origin: PathBuf::from("test.asm"),
+            defines: defines.iter().map(|(k, v)| (k.to_string(), *v)).collect(),
+            ..Default::default()
+        };
+        let names = |lines: Vec<Line>| -> Vec<String> {
+            lines.iter().map(|l| match &l.data {
+                LineData::Instruction {name, params: Parameters::OneRegister(r)} => format!("{} {}", name.to_str(), r.as_u8()),
+                LineData::Instruction {name, ..} => name.to_str().to_owned(),
+                _ => unreachable!(),
+            }).collect()
+        };
+        
+        let (lines, logs) = parse_raw(source, Some(&options(&[("DEBUG", 1), ("DEBUG_LEVEL", 2)])));
+        assert!(logs.is_empty());
+        assert_eq!(names(lines), vec!["NOP", "CLR 1"]);
+        
+        let (lines, logs) = parse_raw(source, Some(&options(&[("DEBUG_LEVEL", 1)])));
+        assert!(logs.is_empty());
+        assert_eq!(names(lines), vec!["CLR 0", "CLR 2"]);
+        
+        let (_, logs) = parse_raw(".if 1 > 0\n.endif", None);
+        assert!(logs[0].message().contains("1:7: unexpected token after .if expression: >"));
+        
+        let (_, logs) = parse_raw("nop\n.if 1\n.ifdef X\n.endif\n.endif\n.endif\n.if 0\n.else\n.else", None);
+        assert_eq!(logs.len(), 3);
+        assert!(logs[0].message().contains("6:1: .endif without a matching .if"));
+        assert!(logs[1].message().contains("duplicate .else for the conditional opened on line 7"));
+        assert!(logs[2].message().contains(":7: conditional opened on line 7 is missing .endif"));
+    }
+    
+    #[test]
+    fn define_immediates() {
+        let options = ParseOptions {
+            origin: PathBuf::from("test.asm"),
+            defines: vec![("MAX".to_owned(), 0x1234)].into_iter().collect(),
+            ..Default::default()
+        };
+        let (lines, logs) = parse_raw("set r0, MAX\njmp MAX\nadd r1, r2, MAX-0x1200", Some(&options));
+        assert!(logs.iter().all(|l| !l.is_error()));
+        assert!(matches!(lines[0].data, LineData::Instruction {params: Parameters::OneRegisterImmediate(_, 0x34), ..}));
+        assert!(matches!(lines[1].data, LineData::Instruction {params: Parameters::LongImmediate(0x1234), ..}));
+        assert!(matches!(lines[2].data, LineData::Instruction {params: Parameters::TwoRegistersImmediate(_, _, 0x34), ..}));
+    }
+    
+    #[test]
+    fn repeat_errors() {
+        let (lines, logs) = parse_raw(".repeat 2\nnop\n.endr\n.endr\n.repeat -1\n.endr\n.repeat 1", None);
+        assert_eq!(lines.len(), 2);
+        assert!(logs[0].message().contains("4:1: .endr without a matching .repeat"));
+        assert!(logs[1].message().contains("repeat count -1 must be between 0 and 65536"));
+        assert!(logs[2].message().contains("repeat opened on line 7 is missing .endr"));
+    }
+    
+    #[test]
+    fn repeat_limit() {
+        let (lines, logs) = parse_raw(".repeat 65536
+nop
+.endr", None);
+        assert_eq!(lines.len(), 0x10000);
+        assert!(logs.is_empty());
+        
+        // Would be 2^32 statements if it ran to the end
+        let (lines, logs) = parse_raw("nop
+.repeat 65536
+.repeat 65536
+nop
+.endr
+.endr
+nop", None);
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].message().contains(":3: repeat opened on line 3 unrolls to more than 65536 statements"));
+        assert!(lines.len() <= 0x10000 + 2);
+        assert_eq!(lines.last().unwrap().line, 6);
+    }
+    
+    #[test]
+    fn numeric_label_errors() {
+        let (_, logs) = parse_raw("jmp 1b\njmp 2f\n2:\njmp 2f", None);
+        assert_eq!(logs.len(), 2);
+        assert!(logs[0].message().contains("1:5: numeric label 1 has no previous definition"));
+        assert!(logs[1].message().contains("4: numeric label 2 has no following definition"));
+    }
+    
+    #[test]
+    fn register_errors() {
+        let message = |source| parse_raw(source, None).1[0].message();
+        assert!(message("clr r16").contains("no such register: r16 (valid range r0-r15)"));
+        assert!(message("clr r255").contains("no such register: r255 (valid range r0-r15)"));
+        assert!(message("clr r300").contains("no such register: r300 (valid range r0-r15)"));
+        assert!(message("clr r").contains("malformed register: r, registers are written r0 to r15"));
+        assert!(message("add r1, rABC").contains("malformed register: rABC"));
+    }
+    
+    #[test]
+    fn codes() {
+        let (_, logs) = parse_raw("frob r1\nadd r1, 0x1FF\nclr r16", None);
+        assert!(matches!(logs[0], Log::Error(0, _, Code::UnknownInstruction, ..)));
+        assert_eq!(logs[1].code(), Code::ImmediateTruncated);
+        assert_eq!(logs[2].code(), Code::RegisterOutOfRange);
+        assert!(logs[0].message().starts_with("[unknown]:1:1: unknown instruction: frob"));
+        for code in Code::iter() {
+            assert_eq!(Code::from_name(code.name()), Some(*code));
+        }
+    }
+    
+    #[test]
+    fn trailing_commas() {
+        for source in ["inc r1,", "set r1,", "ldr r1,", "cmp r1,", "add r1,", "add r1, r2,", "jmp r1,", "li r1,", "lsp sp,"] {
+            let (_, logs) = parse_raw(source, None);
+            assert_eq!(logs.len(), 1, "{}", source);
+            assert!(logs[0].message().contains("trailing ','s are not allowed"), "{}: {}", source, logs[0].message());
+        }
+    }
+    
+    #[test]
+    fn register_spelling() {
+        let register = |source| match parse_raw(source, None) {
+            (lines, logs) if logs.is_empty() => match lines[0].data {
+                LineData::Instruction {params: Parameters::OneRegister(r), ..} => Some(r.as_u8()),
+                _ => None,
+            },
+            _ => None,
+        };
+        assert_eq!(register("clr R5"), Some(5));
+        assert_eq!(register("clr r05"), Some(5));
+        assert_eq!(register("clr R00"), Some(0));
+        assert_eq!(register("clr r0000000000000000000000015"), Some(15));
+        assert!(parse_raw("clr r016", None).1[0].message().contains("no such register: r16"));
+        
+        // A lone `r` is still an identifier, usable as a label
+        assert!(matches!(new_lexer("r").next(), Some(Token::Ident("r"))));
+        assert!(matches!(new_lexer("R").next(), Some(Token::Ident("R"))));
+        assert!(parse_raw("r:\njmp r", None).1.is_empty());
+    }
+    
+    #[test]
+    fn redundant_operands() {
+        let (_, logs) = parse_raw("inc r1, r1\nadd r2, r2, 5\ninc r1, r2\nadd r2, r3, 5\ninc r3", None);
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0].code(), Code::RedundantOperand);
+        assert!(logs[0].message().contains("1:9: `INC r1, r1` is the same as `INC r1`"));
+        assert!(logs[1].message().contains("2:13: `ADD r2, r2, 5` is the same as `ADD r2, 5`"));
+    }
+    
+    #[test]
+    fn nul_bytes() {
+        let (lines, logs) = parse_raw("nop\nnop \0\0\0\0\0\nnop", None);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].code(), Code::Syntax);
+        assert_eq!(logs[0].message(), "[unknown]:2:5: unexpected NUL byte, this looks like a binary file");
+    }
+    
+    #[test]
+    fn length_limits() {
+        let options = ParseOptions {
+            origin: PathBuf::from("test.asm"),
+            max_line_length: 64,
+            max_token_length: 16,
+            ..Default::default()
+        };
+        
+        let source = format!("nop\n.db {}\nnop", "1 ".repeat(1 << 20));
+        let (lines, logs) = parse_raw(&source, Some(&options));
+        assert_eq!(lines.len(), 2);
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].message(), "test.asm:2: line is 2097156 bytes long, the limit is 64");
+        
+        let (lines, logs) = parse_raw(&format!("nop\nlabel_{}: nop", "x".repeat(20)), Some(&options));
+        assert_eq!(lines.len(), 1);
+        assert_eq!(logs[0].message(), "test.asm:2:1: token is 27 bytes long, the limit is 16");
+        
+        // The defaults leave ordinary sources alone
+        let (_, logs) = parse_raw(&format!("label_{}: nop", "x".repeat(20)), None);
+        assert!(logs.is_empty());
+    }
+    
+    #[test]
+    fn operand_order() {
+        let options = |lenient_operands| ParseOptions {
+            origin: PathBuf::from("test.asm"),
+            lenient_operands,
+            ..Default::default()
+        };
+        
+        let (_, logs) = parse_raw("add 5, r1", Some(&options(false)));
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].is_error());
+        assert!(logs[0].message().contains("1:5: ADD takes its register first, the immediate goes last"));
+        
+        let (lines, logs) = parse_raw("add 5, r1\nxor 1 << 2, r3", Some(&options(true)));
+        assert_eq!(logs.len(), 2);
+        assert!(!logs[0].is_error());
+        assert!(logs[0].message().contains("operands of ADD reordered to `ADD r1, 5`"));
+        assert!(logs[1].message().contains("operands of XOR reordered to `XOR r3, 4`"));
+        assert!(matches!(lines[0].data, LineData::Instruction {params: Parameters::OneRegisterImmediate(r, 5), ..} if r.as_u8() == 1));
+        
+        // Order matters for subtraction even when lenient
+        let (_, logs) = parse_raw("sub 5, r1", Some(&options(true)));
+        assert!(logs[0].is_error());
+    }
+    
+    #[test]
+    fn immediate_truncation() {
+        let (_, logs) = parse_raw("add r1, r2, 300\nadd r1, 0x1FF\nadd r1, 255", None);
+        assert_eq!(logs.len(), 2);
+        assert!(logs[0].message().contains("1:13: immediate 300 exceeds 8 bits for ADD; truncated to 0x2C"));
+        assert!(logs[1].message().contains("2:9: immediate 0x1FF exceeds 8 bits for ADD; truncated to 0xFF"));
+    }
+    
+    #[test]
+    fn label_lines() {
+        let (lines, logs) = parse_raw("foo: nop\nbar:\n\n   \nbaz: add r1, r2", None);
+        assert!(logs.is_empty());
+        assert_eq!(lines.len(), 5);
+        assert!(matches!(&lines[0].data, LineData::Label(l) if l == "foo"));
+        assert!(matches!(&lines[1].data, LineData::Instruction {params: Parameters::None, ..}));
+        assert_eq!(lines[1].line, 0);
+        assert!(matches!(&lines[2].data, LineData::Label(l) if l == "bar"));
+        assert!(matches!(&lines[3].data, LineData::Label(l) if l == "baz"));
+        assert!(matches!(&lines[4].data, LineData::Instruction {params: Parameters::TwoRegisters(..), ..}));
+        assert_eq!(lines[4].line, 4);
+    }
+    
+    #[test]
+    fn immediates() {
+        assert_eq!(parse_immediate("0xFF", 8), Ok(0xFF));
+        assert_eq!(parse_immediate("0xff", 8), Ok(0xFF));
+        assert_eq!(parse_immediate("0XFf", 8), Ok(0xFF));
+        assert_eq!(parse_immediate("0x00FF", 8), Ok(0xFF));
+        assert_eq!(parse_immediate("0xFFF", 8), Err(ImmError::Truncated(0xFF)));
+        assert_eq!(parse_immediate("0x1234", 16), Ok(0x1234));
+        assert_eq!(parse_immediate("0x12345", 16), Err(ImmError::Truncated(0x2345)));
+        assert_eq!(parse_immediate("0b11111111", 8), Ok(0xFF));
+        assert_eq!(parse_immediate("0B011111111", 8), Ok(0xFF));
+        assert_eq!(parse_immediate("0b111111111", 8), Err(ImmError::Truncated(0xFF)));
+        assert_eq!(parse_immediate("0x0", 8), Ok(0));
+        assert_eq!(parse_immediate("255", 8), Ok(255));
+        assert!(matches!(parse_immediate("256", 8), Err(ImmError::Invalid(_))));
+        assert!(matches!(parse_immediate("0x", 8), Err(ImmError::Invalid(_))));
+        
+        let (_, logs) = parse_raw("set r0, 0xFF\nset r0, 0X00FF\nset r0, 0xFFF", None);
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].message().ends_with("3:9: immediate 0xFFF will be truncated to an 8-bit value: 0xFF"));
+        
+        let (_, logs) = parse_raw("set r0, 0xDEAD\njmp 0x1DEAD\njmp 0x10000 + 5\nset r0, 0x100 + 5", None);
+        assert_eq!(logs.len(), 4, "{:?}", logs);
+        assert!(logs[0].message().ends_with("immediate 0xDEAD will be truncated to an 8-bit value: 0xAD"));
+        assert!(logs[1].message().ends_with("immediate 0x1DEAD will be truncated to a 16-bit value: 0xDEAD"));
+        assert!(logs[2].message().ends_with("expression value 65541 will be truncated to 16 bits: 0x5"));
+        assert!(logs[3].message().ends_with("expression value 261 will be truncated to 8 bits: 0x5"));
+    }
+    
+    #[test]
+    fn unexpected_characters() {
+        let (lines, logs) = parse_raw("nop\nset r0 @5\nadd r1, r2 ?? r3 $", None);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0].message(), "[unknown]:2:8: unexpected character(s) '@'");
+        assert_eq!(logs[1].message(), "[unknown]:3:12: unexpected character(s) '??'");
+        assert_eq!(logs[1].code(), Code::Syntax);
+    }
+    
+    #[test]
+    fn incomplete_prefix() {
+        let (lines, logs) = parse_raw("set r0, 0x\n.db 0b\n.db 0B\nset r0, 0xG\nset r0, 0x1", None);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(logs.len(), 4);
+        assert_eq!(logs[0].message(), "[unknown]:1:9: incomplete numeric literal '0x'");
+        assert_eq!(logs[1].message(), "[unknown]:2:5: incomplete numeric literal '0b'");
+        assert_eq!(logs[2].message(), "[unknown]:3:5: incomplete numeric literal '0B'");
+        assert_eq!(logs[3].message(), "[unknown]:4:9: invalid numeric literal '0xG'");
+        
+        // `0b` is still a reference once there's a `0:` label to go back to
+        let (_, logs) = parse_raw("0:\njmp 0b", None);
+        assert!(logs.is_empty());
+    }
+    
+    #[test]
+    fn absolute_labels() {
+        let (lines, logs) = parse_raw("vga = 0x8000\nSIZE = 0x10 * 4\nfar = vga\nnone =\nbad = r1", None);
+        assert_eq!(lines.len(), 2);
+        assert!(matches!(&lines[0].data, LineData::AbsoluteLabel(l, 0x8000) if l == "vga"));
+        assert!(matches!(&lines[1].data, LineData::AbsoluteLabel(l, 0x40) if l == "SIZE"));
+        assert_eq!(lines[0].data.to_string(), "vga = 0x8000");
+        
+        let messages: Vec<_> = logs.iter().map(Log::message).collect();
+        assert_eq!(messages, [
+            "[unknown]:3:7: address of far must be a constant",
+            "[unknown]:4:7: expected an address after `none =`",
+            "[unknown]:5:7: expected an address, got: Register(\"1\")",
+        ]);
+    }
+    
+    #[test]
+    fn non_ascii_names() {
+        let (lines, logs) = parse_raw("café: nop\njmp café\n.db \"café\"", None);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0].message(), "[unknown]:1:4: non-ASCII character 'é' in name, only ASCII letters, digits and _ are allowed");
+        assert_eq!(logs[1].message(), "[unknown]:2:8: non-ASCII character 'é' in name, only ASCII letters, digits and _ are allowed");
+    }
+    
+    #[test]
+    fn db_range() {
+        let (_, logs) = parse_raw(".db 256\n.db 0x1FF\n.db 200 + 100", None);
+        assert_eq!(logs.len(), 3);
+        assert!(logs.iter().all(|log| log.is_error() && log.code() == Code::DbRange));
+        assert_eq!(logs[0].message(), "[unknown]:1:5: value 256 does not fit in a byte in .db");
+        assert_eq!(logs[1].message(), "[unknown]:2:5: value 0x1FF does not fit in a byte in .db");
+        assert!(logs[2].message().ends_with(": value 300 does not fit in a byte in .db"));
+        
+        // A minus after a value would subtract, so each negative value gets its own line
+        let (lines, logs) = parse_raw(".db 255 0x00FF\n.db -1\n.db -128", None);
+        assert!(logs.is_empty());
+        let bytes: Vec<u8> = lines.iter().flat_map(|line| match &line.data {
+            LineData::Directive(Directive::DB(data)) => data.iter().map(|b| match b {
+                DataByte::Byte(b) => *b,
+                other => panic!("{:?}", other),
+            }).collect::<Vec<_>>(),
+            other => panic!("{:?}", other),
+        }).collect();
+        assert_eq!(bytes, vec![0xFF, 0xFF, 0xFF, 0x80]);
+        
+        assert_eq!(parse_raw(".db -129", None).1[0].code(), Code::DbRange);
+    }
+    
+    #[test]
+    fn source_underline() {
+        let source = "\tset r99, 1";
+        let (_, logs) = parse_raw(source, None);
+        let (prefix, color, reset) = if cfg!(feature = "no_color") {
+            ("ERROR:", "", "")
+        } else {
+            ("\x1b[1;31mERROR:\x1b[0m", "\x1b[1;31m", "\x1b[0m")
+        };
+        let expected = format!("{}   [unknown]:1:6: no such register: r99 (valid range r0-r15)\n    \tset r99, 1\n    \t    {}^~~{}", prefix, color, reset);
+        assert_eq!(logs[0].display_with_source(source), expected);
+    }
+}
@@ -0,0 +1,10 @@
+use crate::assemble_json;
+
+use wasm_bindgen::prelude::*;
+
+/// Assembles `source` for the browser playground, see `assemble_json` for the shape of the result
+#[wasm_bindgen]
+pub fn assemble_wasm(source: &str) -> JsValue {
+    // The JSON is built by serde_json, so parsing it back can't fail
+    js_sys::JSON::parse(&assemble_json(source).to_string()).unwrap_or(JsValue::NULL)
+}
@@ -0,0 +1,56 @@
+use assembler::{Assembler, Directive, Instruction, Line, LineData, OperandMode, Parameters, Register, RegisterMap};
+use assembler::codegen::{AssembleResult, CodegenOptions, assemble_lines, crc16};
+
+use std::fs;
+use std::sync::Arc;
+
+#[test]
+fn reexports() {
+    let (_, mode, map, _) = Instruction::SET.assemble_info();
+    assert_eq!(mode, OperandMode::OneRegisterAndImmediate);
+    assert_eq!(map, RegisterMap::AA);
+    
+    let origin = Arc::new(String::from("api.rs"));
+    let line = |data| Line {origin: origin.clone(), line: 0, data, comment: None};
+    let lines = [
+        line(LineData::Label(String::from("start"))),
+        line(LineData::Instruction {name: Instruction::SET, params: Parameters::OneRegisterImmediate(Register::from_u8(1).unwrap(), 5)}),
+        line(LineData::Directive(Directive::Line(5))),
+    ];
+    let AssembleResult {bytes, logs, ..} = assemble_lines(&lines, &CodegenOptions::default());
+    assert!(logs.is_empty());
+    assert_eq!(bytes, vec![0b10101001, 0x11, 5, 0, 0]);
+}
+
+#[test]
+fn include_path() {
+    let dir = std::env::temp_dir().join(format!("x69-api-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("consts.asm"), "set r1, 7").unwrap();
+    
+    let (bytes, logs) = Assembler::new().include_path(&dir).fill(0xFF).assemble_str(".include \"consts.asm\"\n.line 5\nnop");
+    assert!(logs.is_empty(), "{:?}", logs);
+    assert_eq!(bytes, vec![0b10101001, 0x11, 7, 0xFF, 0xFF, 0b00101001, 0x00]);
+    
+    let (_, logs) = Assembler::new().assemble_str(".include \"consts.asm\"");
+    assert!(logs[0].is_error());
+}
+
+// Same program as the parse benchmark, the output was recorded before the parser stopped copying every line
+#[test]
+fn golden() {
+    let mut source = String::from(".macro pair a, b\n    push a\n    push b\n.endm\n.ifdef UNSET\n    nop\n.endif\n");
+    for i in 0..1250 {
+        source += &format!("loop{}: set r1, {} // count down\n.again: sub r1, 1\n    jmpnz .again\n    pair r2, r3\n", i, i % 256);
+        source += &format!("    ldr r4, table{0}\n    call loop{0}\n    pop r3; pop r2\ntable{0}: .db 1 2 3 \\\n    4\n", i);
+    }
+    
+    let (bytes, logs) = Assembler::new().assemble_str(&source);
+    assert!(logs.is_empty(), "{:?}", logs);
+    assert_eq!(bytes[..28], [
+        0b10101001, 0x11, 0, 0b10100110, 0x11, 1, 0b11110000, 0x03, 0x00, 0b01010011, 0x22, 0b01010011, 0x33, 0b10010010,
+        0x44, 0x18, 0x00, 0b11000101, 0x00, 0x00, 0b01010100, 0x33, 0b01010100, 0x22, 1, 2, 3, 4,
+    ]);
+    assert_eq!(bytes.len(), 35000);
+    assert_eq!(crc16(&bytes), 0x5719);
+}
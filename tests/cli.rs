@@ -0,0 +1,217 @@
+use assembler::codegen::crc16;
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+// Writes `source` to a fresh temporary file and returns its path
+fn source_file(name: &str, source: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("x69-cli-{}-{}", std::process::id(), name));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("test.asm");
+    fs::write(&path, source).unwrap();
+    path
+}
+
+fn assemble(input: &PathBuf, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_assembler"))
+        .arg(input)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn define() {
+    let input = source_file("define", "
+    .ifdef DEBUG
+        nop
+    .endif
+        set r0, LEVEL");
+    
+    let output = assemble(&input, &["-D", "DEBUG", "--define", "LEVEL=0x2A"]);
+    assert!(output.status.success());
+    assert_eq!(fs::read(input.with_extension("o")).unwrap(), vec![0b00101001, 0x00, 0b10101001, 0x00, 0x2A]);
+    
+    let output = assemble(&input, &["-D", "LEVEL=3"]);
+    assert!(output.status.success());
+    assert_eq!(fs::read(input.with_extension("o")).unwrap(), vec![0b10101001, 0x00, 3]);
+    
+    let output = assemble(&input, &["-D", "LEVEL=banana"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn append_checksum() {
+    let input = source_file("checksum", "nop\nend:");
+    
+    let output = assemble(&input, &["--append-checksum"]);
+    assert!(output.status.success());
+    assert_eq!(fs::read(input.with_extension("o")).unwrap(), vec![0b00101001, 0x00, 0x71, 0xA1]);
+    
+    // Padding comes first so the checksum ends the rom and covers the padding too
+    let output = assemble(&input, &["--append-checksum", "--rom-size", "8", "--fill", "0xFF"]);
+    assert!(output.status.success());
+    let rom = fs::read(input.with_extension("o")).unwrap();
+    assert_eq!(rom.len(), 8);
+    assert_eq!(rom[..6], [0b00101001, 0x00, 0xFF, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(rom[6..], crc16(&rom[..6]).to_le_bytes());
+    
+    let output = assemble(&input, &["--append-checksum", "--rom-size", "3"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("program is 4 bytes, 1 over the rom size of 3"));
+}
+
+#[test]
+fn fill() {
+    let input = source_file("fill", "nop\n.line 4\nnop");
+    
+    let output = assemble(&input, &["--fill", "0xFF"]);
+    assert!(output.status.success());
+    assert_eq!(fs::read(input.with_extension("o")).unwrap(), vec![0b00101001, 0x00, 0xFF, 0xFF, 0b00101001, 0x00]);
+    
+    let output = assemble(&input, &["--fill", "256"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn rom_size() {
+    let input = source_file("rom-size", "nop\nnop");
+    
+    let output = assemble(&input, &["--rom-size", "16", "--fill", "0xFF"]);
+    assert!(output.status.success());
+    let mut expected = vec![0b00101001, 0x00, 0b00101001, 0x00];
+    expected.resize(16, 0xFF);
+    assert_eq!(fs::read(input.with_extension("o")).unwrap(), expected);
+    
+    let input = source_file("rom-size-over", &"nop\n".repeat(10));
+    let output = assemble(&input, &["--rom-size", "16"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("program is 20 bytes, 4 over the rom size of 16"));
+}
+
+#[test]
+fn werror() {
+    let input = source_file("werror", "add r1, 0x1FF");
+    
+    let output = assemble(&input, &[]);
+    assert!(output.status.success());
+    
+    let output = assemble(&input, &["--werror"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("exceeds 8 bits"));
+}
+
+#[test]
+fn silence_warning() {
+    let input = source_file("silence", "add r1, 0x1FF\nfrob");
+    
+    let output = assemble(&input, &["-Wno-truncation"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("exceeds 8 bits"));
+    assert!(stderr.contains("unknown instruction: frob"));
+    
+    let output = assemble(&input, &["-Wno-bananas"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown warning code: bananas"));
+}
+
+#[test]
+fn summary() {
+    let input = source_file("summary", "frob\nadd r1, 0x1FF\nclr r16");
+    let output = assemble(&input, &[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("aborting due to 2 previous errors; 1 warning emitted"));
+    
+    let input = source_file("summary-warnings", "add r1, 0x1FF\nadd r2, 0x1FF");
+    let output = assemble(&input, &[]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with("2 warnings emitted\n"));
+}
+
+#[test]
+fn emit_partial() {
+    let input = source_file("emit-partial", "nop\njmp missing\nret");
+    let output_file = input.with_extension("bin");
+    let _ = fs::remove_file(&output_file);
+    
+    let output = assemble(&input, &["-o", output_file.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(!output_file.exists());
+    
+    let output = assemble(&input, &["--emit-partial", "-o", output_file.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("writing partial output due to 1 previous error"));
+    assert_eq!(fs::read(&output_file).unwrap(), [0b00101001, 0x00, 0b11000100, 0xDE, 0xAD, 0b01010000, 0x00]);
+}
+
+#[test]
+fn check() {
+    let input = source_file("check", "nop\nret");
+    let output_file = input.with_extension("o");
+    let map = input.with_extension("map");
+    let _ = fs::remove_file(&output_file);
+    
+    let output = assemble(&input, &["--check", "--map", map.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(!output_file.exists());
+    assert!(!map.exists());
+    
+    let input = source_file("check-error", "jmp missing");
+    let output = assemble(&input, &["--check"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("missing"));
+    assert!(!input.with_extension("o").exists());
+}
+
+#[test]
+fn quiet() {
+    let input = source_file("quiet", "add r1, 0x1FF\nadd r2, 0x1FF");
+    let output = assemble(&input, &["-q"]);
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    
+    // Errors are still shown and still abort
+    let input = source_file("quiet-errors", "add r1, 0x1FF\nfrob");
+    let output = assemble(&input, &["--quiet"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("truncated"));
+    assert!(stderr.contains("aborting due to 1 previous error\n"));
+}
+
+#[test]
+fn fmt() {
+    let input = source_file("fmt", "aDd   r1 ,r2\nloop:   JMP loop");
+    
+    let output = Command::new(env!("CARGO_BIN_EXE_assembler")).arg("fmt").arg(&input).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "    add r1, r2\nloop:\n    jmp loop\n");
+    
+    let output = Command::new(env!("CARGO_BIN_EXE_assembler")).args(["fmt", "--tab-width", "2"]).arg(&input).output().unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "  add r1, r2\nloop:\n  jmp loop\n");
+    let output = Command::new(env!("CARGO_BIN_EXE_assembler")).args(["fmt", "--use-tabs"]).arg(&input).output().unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "\tadd r1, r2\nloop:\n\tjmp loop\n");
+}
+
+#[test]
+fn debug_lines() {
+    let input = source_file("debug-lines", "nop\n.line 4\nret");
+    let table = input.with_extension("dbg");
+    
+    let output = assemble(&input, &["--debug-lines", table.to_str().unwrap()]);
+    assert!(output.status.success());
+    let table = fs::read(table).unwrap();
+    assert_eq!(table[..4], *b"X69D");
+    assert_eq!(table[table.len() - 10..], [4, 0, 2, 0, 0, 0, 3, 0, 0, 0]);
+}
+
+#[test]
+fn map() {
+    let input = source_file("map", ".entry main\ndata: .db 1 2\nmain: nop");
+    let map = input.with_extension("map");
+    
+    let output = assemble(&input, &["--map", map.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(fs::read_to_string(map).unwrap(), "entry 0x0002 main\n0x0000 data\n0x0002 main\n");
+}
@@ -0,0 +1,12 @@
+// Run with `wasm-pack test --node --features wasm`
+#![cfg(all(feature = "wasm", target_arch = "wasm32"))]
+
+use assembler::assemble_wasm;
+use wasm_bindgen_test::wasm_bindgen_test;
+
+#[wasm_bindgen_test]
+fn byte_count() {
+    let result = assemble_wasm("nop\nset r0, 5");
+    let bytes = js_sys::Reflect::get(&result, &"bytes".into()).unwrap();
+    assert_eq!(js_sys::Array::from(&bytes).length(), 5);
+}
@@ -54,9 +54,12 @@ pub fn static_iter(input: TokenStream) -> TokenStream {
     let len = variants.len();
     let generated = quote! {
         impl #name {
-            pub fn iter() -> std::slice::Iter<'static, Self> {
+            pub fn all() -> &'static [Self] {
                 static ARRAY: [#name; #len] = [#(#name::#variants,)*];
-                ARRAY.iter()
+                &ARRAY
+            }
+            pub fn iter() -> core::slice::Iter<'static, Self> {
+                Self::all().iter()
             }
         }
     };